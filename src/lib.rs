@@ -1,11 +1,78 @@
+use std::cmp::Ordering;
+use std::f64::consts::{LN_10, PI};
 use std::ffi::{c_char, CStr};
-use std::fmt::Debug;
 use std::io::{self, BufRead, Write};
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::thread::JoinHandle;
 
+use chrono::{DateTime, Datelike, FixedOffset, Local, TimeZone, Timelike};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+mod scheduler;
 mod ui;
+mod value;
 pub use ui::{create_window, new_scene, new_sprite, scene_add_sprite};
+pub use value::ScratchValue;
+
+/// Parses a string the way JavaScript's `Number()` does, returning `None` where it would produce `NaN`.
+pub(crate) fn parse_number(string: &str) -> Option<f64> {
+    let string = string.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+    if string.is_empty() {
+        return Some(0.0);
+    }
+    let radix = match string.get(..2) {
+        Some("0x" | "0X") => Some(16),
+        Some("0o" | "0O") => Some(8),
+        Some("0b" | "0B") => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        let digits = &string[2..];
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return None;
+        }
+        return Some(digits.chars().fold(0.0, |acc, c| {
+            acc * radix as f64 + c.to_digit(radix).unwrap() as f64
+        }));
+    }
+    let unsigned = string.strip_prefix(['+', '-']).unwrap_or(string);
+    if unsigned == "Infinity" {
+        return Some(if string.starts_with('-') {
+            f64::NEG_INFINITY
+        } else {
+            f64::INFINITY
+        });
+    }
+    // Rust also accepts "inf" and "nan", which JavaScript does not
+    if !unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    string.parse().ok()
+}
+
+static RNG: Mutex<Option<StdRng>> = Mutex::new(None);
+
+/// Every random number in the runtime comes from here, so that seeding it through `SCRATCH_SEED`
+/// or `seed_random` makes a whole run reproducible.
+pub(crate) fn random() -> f64 {
+    let mut rng = RNG.lock().unwrap();
+    let rng = rng.get_or_insert_with(|| {
+        match std::env::var("SCRATCH_SEED")
+            .ok()
+            .and_then(|seed| seed.trim().parse().ok())
+        {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    });
+    rng.gen()
+}
+
+#[no_mangle]
+pub extern "C" fn seed_random(seed: u64) {
+    *RNG.lock().unwrap() = Some(StdRng::seed_from_u64(seed));
+}
 
 #[no_mangle]
 pub extern "C" fn alloc_string(c_str: *const c_char) -> *mut String {
@@ -40,196 +107,255 @@ pub extern "C" fn ask(question: *const String) -> *mut String {
     Box::into_raw(boxed_input)
 }
 
-fn alloc_empty_vec<T>() -> *mut RwLock<Vec<T>> {
-    let vec: Vec<T> = Vec::new();
-    let boxed_vec = Box::new(RwLock::new(vec));
-    Box::into_raw(boxed_vec)
-}
-
-#[no_mangle]
-pub extern "C" fn alloc_empty_string_vec() -> *mut RwLock<Vec<String>> {
-    alloc_empty_vec::<String>()
-}
+/// A variable's storage, shared with the runtime so that monitors can show it.
+pub type Variable = RwLock<ScratchValue>;
 
 #[no_mangle]
-pub extern "C" fn alloc_empty_f64_vec() -> *mut RwLock<Vec<f64>> {
-    alloc_empty_vec::<f64>()
+pub extern "C" fn alloc_variable(value: *const ScratchValue) -> *mut Variable {
+    let value = unsafe { &*value };
+    Box::into_raw(Box::new(RwLock::new(value.clone())))
 }
 
 #[no_mangle]
-pub extern "C" fn alloc_empty_bool_vec() -> *mut RwLock<Vec<bool>> {
-    alloc_empty_vec::<bool>()
-}
-
-fn clear_vec<T: std::fmt::Debug>(ptr: *mut RwLock<Vec<T>>) {
-    let rwlock = unsafe { ptr.as_ref().unwrap() };
-    let mut vec = rwlock.write().unwrap();
-    vec.clear();
+pub extern "C" fn get_variable(variable: *const Variable) -> *mut ScratchValue {
+    let variable = unsafe { &*variable };
+    Box::into_raw(Box::new(variable.read().unwrap().clone()))
 }
 
 #[no_mangle]
-pub extern "C" fn clear_string_vec(ptr: *mut RwLock<Vec<String>>) {
-    clear_vec::<String>(ptr);
+pub extern "C" fn set_variable(variable: *const Variable, value: *const ScratchValue) {
+    let variable = unsafe { &*variable };
+    let value = unsafe { &*value };
+    *variable.write().unwrap() = value.clone();
+}
+
+/// A Scratch list; items can be any mix of numbers, strings and booleans.
+pub type List = RwLock<Vec<ScratchValue>>;
+
+/// Scratch refuses to grow a list past this many items.
+const LIST_ITEM_LIMIT: usize = 200_000;
+
+#[derive(Debug, PartialEq)]
+enum ListIndex {
+    /// Zero-based
+    Item(usize),
+    All,
+    Invalid,
+}
+
+/// Resolves a list index the way Scratch's `Cast.toListIndex` does, including "last", "random" and "all".
+fn to_list_index(index: &ScratchValue, length: usize, accept_all: bool) -> ListIndex {
+    if let ScratchValue::String(index) = index {
+        match index.as_str() {
+            "all" if accept_all => return ListIndex::All,
+            "all" => return ListIndex::Invalid,
+            "last" if length > 0 => return ListIndex::Item(length - 1),
+            "random" | "any" if length > 0 => {
+                return ListIndex::Item((random() * length as f64) as usize);
+            }
+            "last" | "random" | "any" => return ListIndex::Invalid,
+            _ => {}
+        }
+    }
+    let index = index.to_number().floor();
+    if index < 1.0 || index > length as f64 {
+        ListIndex::Invalid
+    } else {
+        ListIndex::Item(index as usize - 1)
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn clear_f64_vec(ptr: *mut RwLock<Vec<f64>>) {
-    clear_vec::<f64>(ptr);
+pub extern "C" fn alloc_empty_list() -> *mut List {
+    let boxed_list = Box::new(RwLock::new(Vec::new()));
+    Box::into_raw(boxed_list)
 }
 
 #[no_mangle]
-pub extern "C" fn clear_bool_vec(ptr: *mut RwLock<Vec<bool>>) {
-    clear_vec::<bool>(ptr);
-}
-
-fn push_to_vec<T: Debug>(ptr: *mut RwLock<Vec<T>>, value: T) {
+pub extern "C" fn clear_list(ptr: *mut List) {
     let rwlock = unsafe { ptr.as_ref().unwrap() };
     let mut vec = rwlock.write().unwrap();
-    vec.push(value);
+    vec.clear();
 }
 
 #[no_mangle]
-pub extern "C" fn push_to_string_vec(ptr: *mut RwLock<Vec<String>>, value: *const String) {
+pub extern "C" fn push_to_list(ptr: *mut List, value: *const ScratchValue) {
     let value = unsafe { &*value };
-    push_to_vec(ptr, value.clone());
-}
-
-#[no_mangle]
-pub extern "C" fn push_to_f64_vec(ptr: *mut RwLock<Vec<f64>>, value: f64) {
-    push_to_vec(ptr, value);
+    let rwlock = unsafe { ptr.as_ref().unwrap() };
+    let mut vec = rwlock.write().unwrap();
+    if vec.len() < LIST_ITEM_LIMIT {
+        vec.push(value.clone());
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn push_to_bool_vec(ptr: *mut RwLock<Vec<bool>>, value: bool) {
-    push_to_vec(ptr, value);
-}
-
-fn get_vec_element<T: Clone + Debug>(ptr: *const RwLock<Vec<T>>, index: f64, default: T) -> T {
+pub extern "C" fn get_list_element(
+    ptr: *const List,
+    index: *const ScratchValue,
+) -> *mut ScratchValue {
+    let index = unsafe { &*index };
     let rwlock = unsafe { ptr.as_ref().unwrap() };
     let vec = rwlock.read().unwrap();
-    let index = index as usize - 1;
-    vec.get(index).map(|x| x.clone()).unwrap_or(default)
-}
-
-#[no_mangle]
-pub extern "C" fn get_string_vec_element(
-    ptr: *const RwLock<Vec<String>>,
-    index: f64,
-) -> *mut String {
-    let element = get_vec_element(ptr, index, "".to_owned());
+    let element = match to_list_index(index, vec.len(), false) {
+        ListIndex::Item(index) => vec[index].clone(),
+        _ => ScratchValue::default(),
+    };
     let boxed_element = Box::new(element);
     Box::into_raw(boxed_element)
 }
 
 #[no_mangle]
-pub extern "C" fn get_f64_vec_element(ptr: *const RwLock<Vec<f64>>, index: f64) -> f64 {
-    get_vec_element(ptr, index, 0.0)
-}
-
-#[no_mangle]
-pub extern "C" fn get_bool_vec_element(ptr: *const RwLock<Vec<bool>>, index: f64) -> bool {
-    get_vec_element(ptr, index, false)
-}
-
-fn index_of<T: PartialEq + Debug>(ptr: *const RwLock<Vec<T>>, value: T) -> f64 {
+pub extern "C" fn index_of_list_item(ptr: *const List, value: *const ScratchValue) -> f64 {
+    let value = unsafe { &*value };
     let rwlock = unsafe { ptr.as_ref().unwrap() };
     let vec = rwlock.read().unwrap();
     vec.iter()
-        .position(|x| *x == value)
+        .position(|x| compare(x, value) == Ordering::Equal)
         .map(|i| i as f64 + 1.0)
         .unwrap_or(0.0)
 }
 
 #[no_mangle]
-pub extern "C" fn index_of_string(vec: *const RwLock<Vec<String>>, value: *const String) -> f64 {
-    let value = unsafe { &*value };
-    let index = index_of(vec, value.clone());
-    index
+pub extern "C" fn list_contains(ptr: *const List, value: *const ScratchValue) -> bool {
+    index_of_list_item(ptr, value) != 0.0
 }
 
 #[no_mangle]
-pub extern "C" fn index_of_f64(vec: *const RwLock<Vec<f64>>, value: f64) -> f64 {
-    index_of(vec, value)
-}
-
-#[no_mangle]
-pub extern "C" fn index_of_bool(vec: *const RwLock<Vec<bool>>, value: bool) -> f64 {
-    index_of(vec, value)
-}
-
-fn set_vec_element<T>(ptr: *const RwLock<Vec<T>>, index: f64, value: T) {
+pub extern "C" fn list_replace(
+    ptr: *const List,
+    index: *const ScratchValue,
+    value: *const ScratchValue,
+) {
+    let index = unsafe { &*index };
+    let value = unsafe { &*value };
     let rwlock = unsafe { ptr.as_ref().unwrap() };
     let mut vec = rwlock.write().unwrap();
-    let index = index as usize - 1;
-    if index < vec.len() {
-        vec[index] = value;
+    if let ListIndex::Item(index) = to_list_index(index, vec.len(), false) {
+        vec[index] = value.clone();
     }
 }
 
 #[no_mangle]
-pub extern "C" fn set_string_vec_element(
-    ptr: *const RwLock<Vec<String>>,
-    index: f64,
-    value: *const String,
+pub extern "C" fn list_insert_at(
+    ptr: *const List,
+    index: *const ScratchValue,
+    value: *const ScratchValue,
 ) {
+    let index = unsafe { &*index };
     let value = unsafe { &*value };
-    set_vec_element(ptr, index, value.clone());
+    let rwlock = unsafe { ptr.as_ref().unwrap() };
+    let mut vec = rwlock.write().unwrap();
+    // Inserting one past the end appends
+    let ListIndex::Item(index) = to_list_index(index, vec.len() + 1, false) else {
+        return;
+    };
+    if index >= LIST_ITEM_LIMIT {
+        return;
+    }
+    vec.insert(index, value.clone());
+    // A full list drops its last item to make room
+    vec.truncate(LIST_ITEM_LIMIT);
 }
 
 #[no_mangle]
-pub extern "C" fn set_f64_vec_element(ptr: *const RwLock<Vec<f64>>, index: f64, value: f64) {
-    set_vec_element(ptr, index, value);
+pub extern "C" fn list_delete_at(ptr: *const List, index: *const ScratchValue) {
+    let index = unsafe { &*index };
+    let rwlock = unsafe { ptr.as_ref().unwrap() };
+    let mut vec = rwlock.write().unwrap();
+    match to_list_index(index, vec.len(), true) {
+        ListIndex::Item(index) => {
+            vec.remove(index);
+        }
+        ListIndex::All => vec.clear(),
+        ListIndex::Invalid => {}
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn set_bool_vec_element(ptr: *const RwLock<Vec<bool>>, index: f64, value: bool) {
-    set_vec_element(ptr, index, value);
-}
-
-fn len_of_vec<T: Debug>(ptr: *const RwLock<Vec<T>>) -> f64 {
+pub extern "C" fn len_of_list(ptr: *const List) -> f64 {
     let rwlock = unsafe { ptr.as_ref().unwrap() };
     let vec = rwlock.read().unwrap();
     vec.len() as f64
 }
 
-#[no_mangle]
-pub extern "C" fn len_of_string_vec(ptr: *const RwLock<Vec<String>>) -> f64 {
-    len_of_vec::<String>(ptr)
-}
-
-#[no_mangle]
-pub extern "C" fn len_of_f64_vec(ptr: *const RwLock<Vec<f64>>) -> f64 {
-    len_of_vec::<f64>(ptr)
-}
-
-#[no_mangle]
-pub extern "C" fn len_of_bool_vec(ptr: *const RwLock<Vec<bool>>) -> f64 {
-    len_of_vec::<bool>(ptr)
+/// Formats a number like JavaScript's `Number.prototype.toString`, which is what Scratch shows.
+pub(crate) fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        return "NaN".to_owned();
+    }
+    if n == 0.0 {
+        // Including -0
+        return "0".to_owned();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "Infinity" } else { "-Infinity" }.to_owned();
+    }
+    let sign = if n < 0.0 { "-" } else { "" };
+    // Rust's exponent form already has the shortest digits that round-trip, like JavaScript's
+    let scientific = format!("{:e}", n.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    // The decimal point goes after the first `point` digits
+    let point = exponent.parse::<i32>().unwrap() + 1;
+    let formatted = if k <= point && point <= 21 {
+        digits + &"0".repeat((point - k) as usize)
+    } else if 0 < point && point <= 21 {
+        format!(
+            "{}.{}",
+            &digits[..point as usize],
+            &digits[point as usize..]
+        )
+    } else if -6 < point && point <= 0 {
+        format!("0.{}{}", "0".repeat(-point as usize), digits)
+    } else {
+        let exponent_sign = if point - 1 < 0 { "-" } else { "+" };
+        let mantissa = if k == 1 {
+            digits
+        } else {
+            format!("{}.{}", &digits[..1], &digits[1..])
+        };
+        format!("{}e{}{}", mantissa, exponent_sign, (point - 1).abs())
+    };
+    format!("{}{}", sign, formatted)
+}
+
+/// Converts a value to the string Scratch would show for it.
+trait ToScratchString {
+    fn to_scratch_string(&self) -> String;
+}
+
+impl ToScratchString for f64 {
+    fn to_scratch_string(&self) -> String {
+        number_to_string(*self)
+    }
 }
 
-fn cast_vec_to_string<T: ToString>(vec: *const RwLock<Vec<T>>) -> *mut String {
-    let vec = unsafe { &*(vec as *const Vec<T>) };
-    let string = vec
+/// Joins list items the way Scratch shows a list in a string: with nothing between them when every
+/// item is a single letter, and with spaces otherwise.
+fn list_to_string(items: &[ScratchValue]) -> String {
+    let all_single_letters = items.iter().all(|item| match item {
+        ScratchValue::String(string) => string.encode_utf16().count() == 1,
+        _ => false,
+    });
+    let separator = if all_single_letters { "" } else { " " };
+    items
         .iter()
-        .map(|x| x.to_string())
+        .map(|item| item.to_scratch_string())
         .collect::<Vec<String>>()
-        .join(", ");
-    let boxed_str = Box::new(string);
-    Box::into_raw(boxed_str)
-}
-
-#[no_mangle]
-pub extern "C" fn cast_string_vec_to_string(vec: *const RwLock<Vec<String>>) -> *mut String {
-    cast_vec_to_string::<String>(vec)
+        .join(separator)
 }
 
 #[no_mangle]
-pub extern "C" fn cast_f64_vec_to_string(vec: *const RwLock<Vec<f64>>) -> *mut String {
-    cast_vec_to_string::<f64>(vec)
+pub extern "C" fn cast_list_to_string(list: *const List) -> *mut String {
+    let rwlock = unsafe { list.as_ref().unwrap() };
+    let string = list_to_string(&rwlock.read().unwrap());
+    let boxed_str = Box::new(string);
+    Box::into_raw(boxed_str)
 }
 
-fn cast_to_string<T: ToString>(val: T) -> *mut String {
-    let string = val.to_string();
+fn cast_to_string<T: ToScratchString>(val: T) -> *mut String {
+    let string = val.to_scratch_string();
     let boxed_str = Box::new(string);
     Box::into_raw(boxed_str)
 }
@@ -257,16 +383,77 @@ pub extern "C" fn join(string1: *const String, string2: *const String) -> *mut S
 #[no_mangle]
 pub extern "C" fn letter_of(string: *const String, index: f64) -> *mut String {
     let string = unsafe { &*(string) };
-    let index = index as usize - 1;
-    let letter = string
-        .chars()
-        .nth(index)
-        .map(|c| c.to_string())
-        .unwrap_or("".to_string());
+    // Scratch indexes strings by UTF-16 code unit, so an emoji counts as two letters
+    let units = string.encode_utf16().collect::<Vec<u16>>();
+    let index = if index.is_nan() { 0.0 } else { index } - 1.0;
+    let letter = if index < 0.0 || index >= units.len() as f64 {
+        String::new()
+    } else {
+        // Half of a surrogate pair can't be a Rust string, so it becomes U+FFFD
+        String::from_utf16_lossy(&units[index as usize..index as usize + 1])
+    };
     let boxed_str = Box::new(letter);
     Box::into_raw(boxed_str)
 }
 
+#[no_mangle]
+pub extern "C" fn operator_length(string: *const String) -> f64 {
+    let string = unsafe { &*(string) };
+    string.encode_utf16().count() as f64
+}
+
+#[no_mangle]
+pub extern "C" fn operator_contains(string1: *const String, string2: *const String) -> bool {
+    let string1 = unsafe { &*(string1) };
+    let string2 = unsafe { &*(string2) };
+    string1.to_lowercase().contains(&string2.to_lowercase())
+}
+
+/// Compares two values the way Scratch's `Cast.compare` does: as numbers when both are numeric,
+/// and otherwise as case-insensitive strings.
+pub(crate) fn compare(value1: &ScratchValue, value2: &ScratchValue) -> Ordering {
+    let mut n1 = value1.to_js_number();
+    let mut n2 = value2.to_js_number();
+    // Blank strings would otherwise equal 0; Scratch only checks the second one if the first isn't blank
+    if n1 == 0.0 && value1.is_whitespace() {
+        n1 = f64::NAN;
+    } else if n2 == 0.0 && value2.is_whitespace() {
+        n2 = f64::NAN;
+    }
+    if n1.is_nan() || n2.is_nan() {
+        // JavaScript orders strings by UTF-16 code unit
+        let s1 = value1.to_scratch_string().to_lowercase();
+        let s2 = value2.to_scratch_string().to_lowercase();
+        return s1.encode_utf16().cmp(s2.encode_utf16());
+    }
+    // Also makes Infinity equal to itself
+    n1.partial_cmp(&n2).unwrap()
+}
+
+#[no_mangle]
+pub extern "C" fn operator_equals(
+    value1: *const ScratchValue,
+    value2: *const ScratchValue,
+) -> bool {
+    let value1 = unsafe { &*(value1) };
+    let value2 = unsafe { &*(value2) };
+    compare(value1, value2) == Ordering::Equal
+}
+
+#[no_mangle]
+pub extern "C" fn operator_lt(value1: *const ScratchValue, value2: *const ScratchValue) -> bool {
+    let value1 = unsafe { &*(value1) };
+    let value2 = unsafe { &*(value2) };
+    compare(value1, value2) == Ordering::Less
+}
+
+#[no_mangle]
+pub extern "C" fn operator_gt(value1: *const ScratchValue, value2: *const ScratchValue) -> bool {
+    let value1 = unsafe { &*(value1) };
+    let value2 = unsafe { &*(value2) };
+    compare(value1, value2) == Ordering::Greater
+}
+
 #[no_mangle]
 pub extern "C" fn string_eq(string1: *const String, string2: *const String) -> bool {
     let string1 = unsafe { &*(string1) };
@@ -274,6 +461,147 @@ pub extern "C" fn string_eq(string1: *const String, string2: *const String) -> b
     string1 == string2
 }
 
+#[no_mangle]
+pub extern "C" fn operator_mod(n: f64, modulus: f64) -> f64 {
+    // The result takes the sign of the divisor
+    let result = n % modulus;
+    if result / modulus < 0.0 {
+        result + modulus
+    } else {
+        result
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn operator_round(n: f64) -> f64 {
+    n.round()
+}
+
+fn pick_random(from: f64, to: f64, whole_numbers: bool) -> f64 {
+    let from = if from.is_nan() { 0.0 } else { from };
+    let to = if to.is_nan() { 0.0 } else { to };
+    let (low, high) = if from <= to { (from, to) } else { (to, from) };
+    if low == high {
+        return low;
+    }
+    if whole_numbers {
+        low + (random() * (high + 1.0 - low)).floor()
+    } else {
+        random() * (high - low) + low
+    }
+}
+
+/// Whether Scratch treats a number as whole when picking random numbers; NaN counts as whole.
+fn is_int(n: f64) -> bool {
+    // Scratch compares the number with `parseInt` of its string form, which stops at the exponent
+    // that JavaScript starts using from 1e21
+    n.is_nan() || (n.is_finite() && n.fract() == 0.0 && n.abs() < 1e21)
+}
+
+#[no_mangle]
+pub extern "C" fn operator_random(from: f64, to: f64) -> f64 {
+    pick_random(from, to, is_int(from) && is_int(to))
+}
+
+#[no_mangle]
+pub extern "C" fn operator_random_string(from: *const String, to: *const String) -> f64 {
+    let from = unsafe { &*(from) };
+    let to = unsafe { &*(to) };
+    // Scratch only looks for a decimal point, so "1.0" picks a fractional number
+    let whole_numbers = !from.contains('.') && !to.contains('.');
+    let from = parse_number(from).unwrap_or(0.0);
+    let to = parse_number(to).unwrap_or(0.0);
+    pick_random(from, to, whole_numbers)
+}
+
+/// Rounds away the floating point noise in trigonometry results, so that `sin 180` is exactly 0.
+fn round_trig(n: f64) -> f64 {
+    // Adding 0 turns -0 into 0
+    (n * 1e10).round() / 1e10 + 0.0
+}
+
+fn tan_degrees(angle: f64) -> f64 {
+    let angle = angle % 360.0;
+    if angle == 90.0 || angle == -270.0 {
+        f64::INFINITY
+    } else if angle == -90.0 || angle == 270.0 {
+        f64::NEG_INFINITY
+    } else {
+        round_trig((PI * angle / 180.0).tan())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn operator_mathop(operator: *const String, n: f64) -> f64 {
+    let operator = unsafe { &*(operator) };
+    match operator.to_lowercase().as_str() {
+        "abs" => n.abs(),
+        "floor" => n.floor(),
+        "ceiling" => n.ceil(),
+        "sqrt" => n.sqrt(),
+        "sin" => round_trig((PI * n / 180.0).sin()),
+        "cos" => round_trig((PI * n / 180.0).cos()),
+        "tan" => tan_degrees(n),
+        // Written out like Scratch does, which can differ from to_degrees and log10 in the last digit
+        "asin" => n.asin() * 180.0 / PI,
+        "acos" => n.acos() * 180.0 / PI,
+        "atan" => n.atan() * 180.0 / PI,
+        "ln" => n.ln(),
+        "log" => n.ln() / LN_10,
+        "e ^" => n.exp(),
+        "10 ^" => 10f64.powf(n),
+        _ => 0.0,
+    }
+}
+
+/// When set, the date and time reporters see this moment instead of the system clock.
+static MOCKED_TIME: RwLock<Option<DateTime<FixedOffset>>> = RwLock::new(None);
+
+fn current_time() -> DateTime<FixedOffset> {
+    match *MOCKED_TIME.read().unwrap() {
+        Some(time) => time,
+        None => Local::now().fixed_offset(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mock_current_time(unix_millis: f64, utc_offset_minutes: i32) {
+    let offset = FixedOffset::east_opt(utc_offset_minutes * 60).unwrap();
+    let time = offset.timestamp_millis_opt(unix_millis as i64).unwrap();
+    *MOCKED_TIME.write().unwrap() = Some(time);
+}
+
+#[no_mangle]
+pub extern "C" fn unmock_current_time() {
+    *MOCKED_TIME.write().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn sensing_current(unit: *const String) -> f64 {
+    let unit = unsafe { &*(unit) };
+    let time = current_time();
+    let value = match unit.to_lowercase().as_str() {
+        "year" => time.year() as u32,
+        "month" => time.month(),
+        "date" => time.day(),
+        // Sunday is 1
+        "dayofweek" | "day of week" => time.weekday().number_from_sunday(),
+        "hour" => time.hour(),
+        "minute" => time.minute(),
+        "second" => time.second(),
+        _ => 0,
+    };
+    value as f64
+}
+
+#[no_mangle]
+pub extern "C" fn sensing_days_since_2000() -> f64 {
+    // Scratch cancels out the local time zone, so this counts from midnight UTC
+    const MILLIS_AT_2000: f64 = 946_684_800_000.0;
+    const MILLIS_PER_DAY: f64 = 86_400_000.0;
+    (current_time().timestamp_millis() as f64 - MILLIS_AT_2000) / MILLIS_PER_DAY
+}
+
 #[no_mangle]
 pub extern "C" fn spawn_thread(unsafe_fn: extern "C" fn()) -> *mut JoinHandle<()> {
     let handle = std::thread::spawn(move || {
@@ -288,3 +616,376 @@ pub extern "C" fn join_thread(handle: *mut JoinHandle<()>) {
     let handle = unsafe { Box::from_raw(handle) };
     handle.join().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::{cast_value_to_bool, cast_value_to_f64, cast_value_to_string, value_kind};
+
+    /// The mocked time is global, so tests that mock it take turns.
+    static TIME_LOCK: Mutex<()> = Mutex::new(());
+
+    /// 2024-03-10 23:30:45 UTC, a Sunday.
+    const SUNDAY_NIGHT_UTC: f64 = 1_710_113_445_000.0;
+
+    fn current(unit: &str) -> f64 {
+        sensing_current(&unit.to_owned())
+    }
+
+    #[test]
+    fn current_reports_local_time() {
+        let _lock = TIME_LOCK.lock().unwrap();
+        mock_current_time(SUNDAY_NIGHT_UTC, -5 * 60);
+        assert_eq!(current("year"), 2024.0);
+        assert_eq!(current("month"), 3.0);
+        assert_eq!(current("date"), 10.0);
+        assert_eq!(current("dayofweek"), 1.0);
+        assert_eq!(current("hour"), 18.0);
+        assert_eq!(current("minute"), 30.0);
+        assert_eq!(current("second"), 45.0);
+
+        // Two hours east of UTC it's already Monday
+        mock_current_time(SUNDAY_NIGHT_UTC, 2 * 60);
+        assert_eq!(current("date"), 11.0);
+        assert_eq!(current("day of week"), 2.0);
+        assert_eq!(current("hour"), 1.0);
+        unmock_current_time();
+    }
+
+    #[test]
+    fn days_since_2000_ignores_the_utc_offset() {
+        let _lock = TIME_LOCK.lock().unwrap();
+        mock_current_time(SUNDAY_NIGHT_UTC, -5 * 60);
+        assert_eq!(sensing_days_since_2000(), 8835.9796875);
+        mock_current_time(SUNDAY_NIGHT_UTC, 2 * 60);
+        assert_eq!(sensing_days_since_2000(), 8835.9796875);
+        mock_current_time(946_684_800_000.0, 0);
+        assert_eq!(sensing_days_since_2000(), 0.0);
+        unmock_current_time();
+    }
+
+    fn string(value: &str) -> ScratchValue {
+        ScratchValue::String(value.to_owned())
+    }
+
+    #[test]
+    fn compare_numbers_numerically_and_strings_case_insensitively() {
+        assert_eq!(compare(&string("10"), &string("9")), Ordering::Greater);
+        assert_eq!(
+            compare(&string("1"), &ScratchValue::Number(1.0)),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare(&string("0x10"), &ScratchValue::Number(16.0)),
+            Ordering::Equal
+        );
+        assert_eq!(compare(&string("apple"), &string("APPLE")), Ordering::Equal);
+        assert_eq!(compare(&string("a"), &string("B")), Ordering::Less);
+        assert_eq!(
+            compare(&ScratchValue::Bool(true), &string("TRUE")),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn compare_whitespace_strings_as_strings() {
+        // Blank strings would be 0 as numbers, but Scratch compares them as text
+        assert_eq!(
+            compare(&string(" "), &ScratchValue::Number(0.0)),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(&string(""), &ScratchValue::Number(0.0)),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(&ScratchValue::Number(0.0), &string("\t")),
+            Ordering::Greater
+        );
+        assert_eq!(compare(&string(" "), &string(" ")), Ordering::Equal);
+    }
+
+    #[test]
+    fn compare_infinity() {
+        assert_eq!(
+            compare(&string("Infinity"), &ScratchValue::Number(f64::INFINITY)),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare(&string("-Infinity"), &string("-Infinity")),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare(&string("Infinity"), &string("1e308")),
+            Ordering::Greater
+        );
+        // Only the exact spelling is a number
+        assert_eq!(
+            compare(&string("infinity"), &string("Infinity")),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare(&string("inf"), &ScratchValue::Number(f64::INFINITY)),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn numbers_format_like_javascript() {
+        assert_eq!(number_to_string(1e21), "1e+21");
+        assert_eq!(number_to_string(1e20), "100000000000000000000");
+        assert_eq!(number_to_string(1e-7), "1e-7");
+        assert_eq!(number_to_string(1.5e-7), "1.5e-7");
+        assert_eq!(number_to_string(0.000001), "0.000001");
+        assert_eq!(number_to_string(-0.0), "0");
+        assert_eq!(number_to_string(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(number_to_string(-2.5), "-2.5");
+        assert_eq!(number_to_string(f64::MAX), "1.7976931348623157e+308");
+    }
+
+    #[test]
+    fn special_numbers_format_like_javascript() {
+        assert_eq!(number_to_string(f64::NAN), "NaN");
+        assert_eq!(number_to_string(f64::INFINITY), "Infinity");
+        assert_eq!(number_to_string(f64::NEG_INFINITY), "-Infinity");
+    }
+
+    #[test]
+    fn list_indices_accept_scratch_menu_items() {
+        assert_eq!(to_list_index(&string("last"), 3, false), ListIndex::Item(2));
+        assert_eq!(to_list_index(&string("last"), 0, false), ListIndex::Invalid);
+        assert_eq!(to_list_index(&string("all"), 3, true), ListIndex::All);
+        assert_eq!(to_list_index(&string("all"), 3, false), ListIndex::Invalid);
+        assert_eq!(
+            to_list_index(&string("random"), 0, false),
+            ListIndex::Invalid
+        );
+        for _ in 0..20 {
+            for random in ["random", "any"] {
+                let ListIndex::Item(index) = to_list_index(&string(random), 3, false) else {
+                    panic!("{} should pick an item", random);
+                };
+                assert!(index < 3);
+            }
+        }
+    }
+
+    #[test]
+    fn list_indices_are_floored_and_range_checked() {
+        assert_eq!(
+            to_list_index(&ScratchValue::Number(0.0), 3, false),
+            ListIndex::Invalid
+        );
+        assert_eq!(
+            to_list_index(&ScratchValue::Number(1.9), 3, false),
+            ListIndex::Item(0)
+        );
+        assert_eq!(to_list_index(&string("1.9"), 3, false), ListIndex::Item(0));
+        assert_eq!(
+            to_list_index(&ScratchValue::Number(3.0), 3, false),
+            ListIndex::Item(2)
+        );
+        assert_eq!(
+            to_list_index(&ScratchValue::Number(4.0), 3, false),
+            ListIndex::Invalid
+        );
+        assert_eq!(to_list_index(&string("Last"), 3, false), ListIndex::Invalid);
+        assert_eq!(
+            to_list_index(&ScratchValue::Number(f64::NAN), 3, false),
+            ListIndex::Invalid
+        );
+    }
+
+    #[test]
+    fn inserting_one_past_the_end_appends() {
+        let list = alloc_empty_list();
+        push_to_list(list, &string("a"));
+        push_to_list(list, &string("b"));
+        list_insert_at(list, &ScratchValue::Number(3.0), &string("c"));
+        list_insert_at(list, &ScratchValue::Number(5.0), &string("ignored"));
+        list_insert_at(list, &ScratchValue::Number(0.0), &string("ignored"));
+        let list = unsafe { Box::from_raw(list) };
+        let items = list
+            .read()
+            .unwrap()
+            .iter()
+            .map(|item| item.to_scratch_string())
+            .collect::<Vec<String>>();
+        assert_eq!(items, ["a", "b", "c"]);
+    }
+
+    fn mathop(operator: &str, n: f64) -> f64 {
+        operator_mathop(&operator.to_owned(), n)
+    }
+
+    #[test]
+    fn mod_takes_the_sign_of_the_divisor() {
+        assert_eq!(operator_mod(5.0, 3.0), 2.0);
+        assert_eq!(operator_mod(-5.0, 3.0), 1.0);
+        assert_eq!(operator_mod(5.0, -3.0), -1.0);
+        assert_eq!(operator_mod(-5.0, -3.0), -2.0);
+        assert_eq!(operator_mod(6.0, -3.0), 0.0);
+        assert!(operator_mod(5.0, 0.0).is_nan());
+    }
+
+    #[test]
+    fn round_goes_half_away_from_zero() {
+        assert_eq!(operator_round(2.5), 3.0);
+        assert_eq!(operator_round(-2.5), -3.0);
+        assert_eq!(operator_round(2.4), 2.0);
+    }
+
+    #[test]
+    fn tan_is_infinite_at_odd_right_angles() {
+        assert_eq!(mathop("tan", 90.0), f64::INFINITY);
+        assert_eq!(mathop("tan", -270.0), f64::INFINITY);
+        assert_eq!(mathop("tan", 450.0), f64::INFINITY);
+        assert_eq!(mathop("tan", 270.0), f64::NEG_INFINITY);
+        assert_eq!(mathop("tan", -90.0), f64::NEG_INFINITY);
+        assert_eq!(mathop("tan", 45.0), 1.0);
+        assert_eq!(mathop("tan", 180.0), 0.0);
+    }
+
+    #[test]
+    fn trigonometry_rounds_to_10_decimal_places() {
+        assert_eq!(mathop("sin", 30.0), 0.5);
+        assert_eq!(mathop("cos", 60.0), 0.5);
+        assert_eq!(mathop("sin", 180.0), 0.0);
+        assert_eq!(mathop("cos", 90.0), 0.0);
+        assert_eq!(mathop("sin", 1.0), 0.0174524064);
+    }
+
+    #[test]
+    fn trigonometry_never_returns_negative_zero() {
+        for (operator, n) in [
+            ("sin", -180.0),
+            ("sin", 360.0),
+            ("cos", 270.0),
+            ("tan", -180.0),
+        ] {
+            let result = mathop(operator, n);
+            assert_eq!(result, 0.0);
+            assert!(result.is_sign_positive(), "{operator} {n} is -0");
+        }
+    }
+
+    fn letter(string: &str, index: f64) -> String {
+        *unsafe { Box::from_raw(letter_of(&string.to_owned(), index)) }
+    }
+
+    #[test]
+    fn strings_are_indexed_by_utf16_unit() {
+        let emoji = "a\u{1F600}b";
+        assert_eq!(operator_length(&emoji.to_owned()), 4.0);
+        assert_eq!(letter(emoji, 1.0), "a");
+        assert_eq!(letter(emoji, 2.0), "\u{FFFD}");
+        assert_eq!(letter(emoji, 3.0), "\u{FFFD}");
+        assert_eq!(letter(emoji, 4.0), "b");
+        // A combining accent is a letter of its own
+        let accented = "e\u{301}";
+        assert_eq!(operator_length(&accented.to_owned()), 2.0);
+        assert_eq!(letter(accented, 1.0), "e");
+        assert_eq!(letter(accented, 2.0), "\u{301}");
+    }
+
+    #[test]
+    fn letters_out_of_range_are_empty() {
+        assert_eq!(letter("abc", 0.0), "");
+        assert_eq!(letter("abc", -1.0), "");
+        assert_eq!(letter("abc", 0.5), "");
+        assert_eq!(letter("abc", 4.0), "");
+        assert_eq!(letter("abc", f64::NAN), "");
+        assert_eq!(letter("abc", f64::INFINITY), "");
+        assert_eq!(letter("abc", 1.9), "a");
+        assert_eq!(letter("", 1.0), "");
+    }
+
+    #[test]
+    fn contains_ignores_case() {
+        let contains =
+            |string: &str, part: &str| operator_contains(&string.to_owned(), &part.to_owned());
+        assert!(contains("Hello World", "WORLD"));
+        assert!(contains("\u{C4}PFEL", "\u{E4}pf"));
+        assert!(contains("abc", ""));
+        assert!(!contains("abc", "abd"));
+        assert!(contains("a\u{1F600}b", "\u{1F600}B"));
+    }
+
+    fn joined(items: &[ScratchValue]) -> String {
+        let list = alloc_empty_list();
+        for item in items {
+            push_to_list(list, item);
+        }
+        let string = *unsafe { Box::from_raw(cast_list_to_string(list)) };
+        drop(unsafe { Box::from_raw(list) });
+        string
+    }
+
+    #[test]
+    fn single_letter_lists_join_without_spaces() {
+        assert_eq!(
+            joined(&[string("a"), string("b"), string("\u{E9}")]),
+            "ab\u{E9}"
+        );
+        assert_eq!(joined(&[]), "");
+    }
+
+    #[test]
+    fn other_lists_join_with_spaces() {
+        assert_eq!(joined(&[string("a"), string("bc")]), "a bc");
+        // Only strings count as letters, so single digits are still spaced
+        assert_eq!(joined(&[string("a"), ScratchValue::Number(1.0)]), "a 1");
+        let numbers = [1.0, 2.5, 3.0].map(ScratchValue::Number);
+        assert_eq!(joined(&numbers), "1 2.5 3");
+        // An emoji is two UTF-16 units, so it isn't a single letter
+        assert_eq!(joined(&[string("a"), string("\u{1F600}")]), "a \u{1F600}");
+    }
+
+    #[test]
+    fn values_cast_to_bool_like_scratch() {
+        for falsy in ["", "0", "false", "FALSE"] {
+            assert!(!cast_value_to_bool(&string(falsy)), "{falsy:?} is true");
+        }
+        // Anything else is true, even if it looks like zero or is blank
+        for truthy in [" ", "0.0", "00", "no", "true"] {
+            assert!(cast_value_to_bool(&string(truthy)), "{truthy:?} is false");
+        }
+        assert!(!cast_value_to_bool(&ScratchValue::Number(0.0)));
+        assert!(!cast_value_to_bool(&ScratchValue::Number(f64::NAN)));
+        assert!(cast_value_to_bool(&ScratchValue::Number(-1.0)));
+        assert!(!cast_value_to_bool(&ScratchValue::Bool(false)));
+    }
+
+    #[test]
+    fn values_cast_to_number_like_scratch() {
+        assert_eq!(cast_value_to_f64(&ScratchValue::Number(f64::NAN)), 0.0);
+        assert_eq!(cast_value_to_f64(&string("abc")), 0.0);
+        assert_eq!(cast_value_to_f64(&string("")), 0.0);
+        assert_eq!(cast_value_to_f64(&string(" 12 ")), 12.0);
+        assert_eq!(cast_value_to_f64(&string("Infinity")), f64::INFINITY);
+        assert_eq!(cast_value_to_f64(&ScratchValue::Bool(true)), 1.0);
+        assert!(ScratchValue::Number(f64::NAN).to_js_number().is_nan());
+    }
+
+    #[test]
+    fn values_cast_to_string_like_scratch() {
+        let cast = |value: ScratchValue| *unsafe { Box::from_raw(cast_value_to_string(&value)) };
+        assert_eq!(cast(ScratchValue::Number(1.0)), "1");
+        assert_eq!(cast(ScratchValue::Number(f64::NAN)), "NaN");
+        assert_eq!(cast(ScratchValue::Bool(true)), "true");
+        assert_eq!(cast(string("apple")), "apple");
+        assert_eq!(value_kind(&ScratchValue::default()), 1);
+    }
+
+    #[test]
+    fn whole_numbers_end_where_javascript_switches_to_exponents() {
+        assert!(is_int(3.0));
+        assert!(is_int(-0.0));
+        assert!(is_int(f64::NAN));
+        assert!(is_int(1e20));
+        assert!(!is_int(1e21));
+        assert!(!is_int(-1e21));
+        assert!(!is_int(f64::INFINITY));
+        assert!(!is_int(1.5));
+    }
+}
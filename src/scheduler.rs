@@ -0,0 +1,438 @@
+//! Runs scripts as cooperative tasks on one thread, stepping them the way Scratch's sequencer does.
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use corosensei::{Coroutine, CoroutineResult, Yielder};
+
+/// Scratch steps its scripts 30 times a second.
+const STEP_TIME: Duration = Duration::from_nanos(1_000_000_000 / 30);
+/// Like Scratch, leave a quarter of each step for drawing.
+const WORK_TIME: Duration = Duration::from_nanos(STEP_TIME.as_nanos() as u64 * 3 / 4);
+
+/// Why a task gave control back to the scheduler.
+#[derive(Copy, Clone, PartialEq)]
+enum Suspend {
+    /// At the end of a loop; the task can run again in the same step.
+    Yield,
+    /// Waiting for something; the task is done until the next step.
+    Tick,
+}
+
+/// Lets whoever spawned a task find out when it finishes, or stop it early.
+#[derive(Default)]
+pub(crate) struct TaskHandle {
+    done: Mutex<bool>,
+    finished: Condvar,
+    stop_requested: AtomicBool,
+    on_stop: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+impl TaskHandle {
+    pub(crate) fn is_done(&self) -> bool {
+        *self.done.lock().unwrap()
+    }
+
+    /// Stops the task before it next resumes.
+    pub(crate) fn stop(&self) {
+        self.stop_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for the task to finish; inside a task the other tasks keep running meanwhile.
+    pub(crate) fn join(&self) {
+        if in_task() {
+            while !self.is_done() {
+                yield_tick();
+            }
+            return;
+        }
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.finished.wait(done).unwrap();
+        }
+    }
+
+    fn finish(&self) {
+        *self.done.lock().unwrap() = true;
+        self.finished.notify_all();
+    }
+}
+
+struct Task {
+    coroutine: Coroutine<(), Suspend, ()>,
+    handle: Arc<TaskHandle>,
+    ticked: bool,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    tasks: Vec<Task>,
+    last_step: Option<Instant>,
+}
+
+/// Scripts waiting to become tasks; spawning can happen from any thread, even inside a task.
+static SPAWNED: Mutex<Vec<(extern "C" fn(), Arc<TaskHandle>)>> = Mutex::new(Vec::new());
+static HATS_AS_TASKS: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static SCHEDULER: RefCell<Scheduler> = RefCell::default();
+    /// Set while a task is running on this thread.
+    static CURRENT_YIELDER: Cell<Option<*const Yielder<(), Suspend>>> = const { Cell::new(None) };
+    static CURRENT_TASK: RefCell<Option<Arc<TaskHandle>>> = const { RefCell::new(None) };
+    /// Per thread, since only the tasks' own changes should end their step.
+    static REDRAW_REQUESTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Tells the scheduler that something visible changed, which ends the current step.
+pub(crate) fn request_redraw() {
+    REDRAW_REQUESTED.set(true);
+}
+
+pub(crate) fn hats_are_tasks() -> bool {
+    HATS_AS_TASKS.load(Ordering::SeqCst)
+}
+
+pub(crate) fn in_task() -> bool {
+    CURRENT_YIELDER.get().is_some()
+}
+
+fn suspend(reason: Suspend) {
+    let Some(yielder) = CURRENT_YIELDER.get() else {
+        return;
+    };
+    unsafe { &*yielder }.suspend(reason);
+    // Other tasks ran in the meantime
+    CURRENT_YIELDER.set(Some(yielder));
+}
+
+/// Sets how to undo what the current task leaves behind if it's stopped while suspended, such as
+/// a question it's waiting on. Does nothing outside a task.
+pub(crate) fn set_on_stop(on_stop: Option<Box<dyn FnOnce() + Send>>) {
+    CURRENT_TASK.with_borrow(|task| {
+        if let Some(task) = task {
+            *task.on_stop.lock().unwrap() = on_stop;
+        }
+    });
+}
+
+/// Gives the rest of the step to other tasks.
+pub(crate) fn yield_tick() {
+    suspend(Suspend::Tick);
+}
+
+/// Waits without blocking other tasks; scripts on their own thread just sleep.
+pub(crate) fn wait(duration: Duration) {
+    if !in_task() {
+        thread::sleep(duration);
+        return;
+    }
+    request_redraw();
+    let end = Instant::now() + duration;
+    // Even a wait of 0 seconds lets the other tasks run
+    loop {
+        yield_tick();
+        if Instant::now() >= end {
+            break;
+        }
+    }
+}
+
+impl Scheduler {
+    fn add_spawned(&mut self) {
+        for (script, handle) in SPAWNED.lock().unwrap().drain(..) {
+            let coroutine = Coroutine::new(move |yielder: &Yielder<(), Suspend>, ()| {
+                CURRENT_YIELDER.set(Some(yielder as *const _));
+                script();
+            });
+            self.tasks.push(Task {
+                coroutine,
+                handle,
+                ticked: false,
+            });
+        }
+    }
+    /// Runs every task once, returning how many of them can still run in this step.
+    fn tick(&mut self) -> usize {
+        let mut active = 0;
+        for task in self.tasks.iter_mut().filter(|task| !task.ticked) {
+            if task.handle.stop_requested.load(Ordering::SeqCst) {
+                // Compiled scripts have no unwind information, so the stack is discarded rather than
+                // unwound. Whatever the script still owned, like strings held across a yield, leaks.
+                unsafe { task.coroutine.force_reset() };
+                if let Some(on_stop) = task.handle.on_stop.lock().unwrap().take() {
+                    on_stop();
+                }
+                continue;
+            }
+            CURRENT_TASK.set(Some(task.handle.clone()));
+            let result = task.coroutine.resume(());
+            CURRENT_YIELDER.set(None);
+            CURRENT_TASK.set(None);
+            match result {
+                CoroutineResult::Yield(Suspend::Yield) => active += 1,
+                CoroutineResult::Yield(Suspend::Tick) => task.ticked = true,
+                CoroutineResult::Return(()) => {}
+            }
+        }
+        self.tasks.retain(|task| {
+            if task.coroutine.done() {
+                task.handle.finish();
+            }
+            !task.coroutine.done()
+        });
+        active
+    }
+    /// Keeps running tasks until one asks for a redraw, they all wait, or the step's time is used up.
+    fn step(&mut self) {
+        let start = Instant::now();
+        REDRAW_REQUESTED.set(false);
+        for task in &mut self.tasks {
+            task.ticked = false;
+        }
+        loop {
+            self.add_spawned();
+            let active = self.tick();
+            if active == 0 || start.elapsed() >= WORK_TIME || REDRAW_REQUESTED.get() {
+                break;
+            }
+        }
+        self.add_spawned();
+    }
+    /// Steps at Scratch's frame rate however often it's called.
+    fn step_if_due(&mut self) {
+        let now = Instant::now();
+        // Allow a little slack so that a 60 Hz display steps on every other frame
+        if let Some(last_step) = self.last_step {
+            if now.duration_since(last_step) + STEP_TIME / 4 < STEP_TIME {
+                return;
+            }
+        }
+        self.last_step = Some(now);
+        self.step();
+    }
+}
+
+/// Called by the window once per frame.
+pub(crate) fn step_frame() {
+    SCHEDULER.with_borrow_mut(|scheduler| scheduler.step_if_due());
+}
+
+pub(crate) fn spawn(script: extern "C" fn()) -> Arc<TaskHandle> {
+    let handle = Arc::new(TaskHandle::default());
+    SPAWNED.lock().unwrap().push((script, handle.clone()));
+    handle
+}
+
+/// The handle must be freed with `scheduler_free_task`.
+#[no_mangle]
+pub extern "C" fn scheduler_spawn(script: extern "C" fn()) -> *mut Arc<TaskHandle> {
+    Box::into_raw(Box::new(spawn(script)))
+}
+
+#[no_mangle]
+pub extern "C" fn scheduler_task_done(handle: *const Arc<TaskHandle>) -> bool {
+    let handle = unsafe { &*handle };
+    handle.is_done()
+}
+
+/// Waits for a task to finish, e.g. for "broadcast and wait".
+#[no_mangle]
+pub extern "C" fn scheduler_join(handle: *const Arc<TaskHandle>) {
+    let handle = unsafe { &*handle };
+    handle.join();
+}
+
+#[no_mangle]
+pub extern "C" fn scheduler_stop(handle: *const Arc<TaskHandle>) {
+    let handle = unsafe { &*handle };
+    handle.stop();
+}
+
+#[no_mangle]
+pub extern "C" fn scheduler_free_task(handle: *mut Arc<TaskHandle>) {
+    unsafe {
+        let _ = Box::from_raw(handle);
+    }
+}
+
+/// Makes hat blocks start their scripts as tasks instead of on their own threads.
+#[no_mangle]
+pub extern "C" fn scheduler_enable_hats() {
+    HATS_AS_TASKS.store(true, Ordering::SeqCst);
+}
+
+/// Called by compiled code at the end of each loop iteration.
+#[no_mangle]
+pub extern "C" fn scheduler_yield() {
+    suspend(Suspend::Yield);
+}
+
+#[no_mangle]
+pub extern "C" fn scheduler_wait(secs: f64) {
+    wait(Duration::from_secs_f64(secs.max(0.0)));
+}
+
+/// Runs tasks on the calling thread until all of them finish, for projects without a window.
+#[no_mangle]
+pub extern "C" fn scheduler_run() {
+    SCHEDULER.with_borrow_mut(|scheduler| loop {
+        scheduler.add_spawned();
+        if scheduler.tasks.is_empty() {
+            break;
+        }
+        let start = Instant::now();
+        scheduler.step();
+        thread::sleep(STEP_TIME.saturating_sub(start.elapsed()));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    /// Spawned scripts and their log are global, so tests take turns.
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+    static LOG: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+    static JOINED: Mutex<Option<Arc<TaskHandle>>> = Mutex::new(None);
+
+    fn serial() -> MutexGuard<'static, ()> {
+        let guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        LOG.lock().unwrap().clear();
+        guard
+    }
+
+    fn log(entry: &'static str) {
+        LOG.lock().unwrap().push(entry);
+    }
+
+    fn logged() -> Vec<&'static str> {
+        LOG.lock().unwrap().clone()
+    }
+
+    extern "C" fn loop_a() {
+        for _ in 0..3 {
+            log("a");
+            scheduler_yield();
+        }
+    }
+
+    extern "C" fn loop_b() {
+        for _ in 0..3 {
+            log("b");
+            scheduler_yield();
+        }
+    }
+
+    extern "C" fn redrawing_loop_a() {
+        for _ in 0..3 {
+            log("a");
+            request_redraw();
+            scheduler_yield();
+        }
+    }
+
+    /// Logs once per step.
+    extern "C" fn steps() {
+        for _ in 0..3 {
+            log("|");
+            yield_tick();
+        }
+    }
+
+    extern "C" fn wait_a() {
+        log("a start");
+        scheduler_wait(0.05);
+        log("a end");
+    }
+
+    extern "C" fn forever_a() {
+        loop {
+            log("a");
+            yield_tick();
+        }
+    }
+
+    extern "C" fn ask_forever() {
+        set_on_stop(Some(Box::new(|| log("withdrawn"))));
+        forever_a();
+    }
+
+    extern "C" fn join_b() {
+        let handle = JOINED.lock().unwrap().clone().unwrap();
+        handle.join();
+        log("b");
+    }
+
+    #[test]
+    fn loops_interleave_until_the_step_ends() {
+        let _guard = serial();
+        spawn(loop_a);
+        spawn(loop_b);
+        spawn(steps);
+        scheduler_run();
+        assert_eq!(logged(), ["a", "b", "|", "a", "b", "a", "b", "|", "|"]);
+    }
+
+    #[test]
+    fn redraw_ends_the_step() {
+        let _guard = serial();
+        spawn(redrawing_loop_a);
+        spawn(loop_b);
+        spawn(steps);
+        scheduler_run();
+        assert_eq!(logged(), ["a", "b", "|", "a", "b", "|", "a", "b", "|"]);
+    }
+
+    #[test]
+    fn wait_lets_other_tasks_run() {
+        let _guard = serial();
+        let start = Instant::now();
+        spawn(wait_a);
+        spawn(loop_b);
+        scheduler_run();
+        assert_eq!(logged(), ["a start", "b", "b", "b", "a end"]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn join_waits_for_the_task() {
+        let _guard = serial();
+        *JOINED.lock().unwrap() = Some(spawn(steps));
+        spawn(join_b);
+        scheduler_run();
+        assert_eq!(logged(), ["|", "|", "|", "b"]);
+    }
+
+    #[test]
+    fn stop_ends_the_task() {
+        let _guard = serial();
+        let handle = spawn(forever_a);
+        SCHEDULER.with_borrow_mut(|scheduler| {
+            scheduler.step();
+            scheduler.step();
+            assert!(!handle.is_done());
+            handle.stop();
+            scheduler.step();
+            assert!(scheduler.tasks.is_empty());
+        });
+        assert!(handle.is_done());
+        assert_eq!(logged(), ["a", "a"]);
+    }
+
+    #[test]
+    fn stopping_undoes_what_the_task_left_behind() {
+        let _guard = serial();
+        let handle = spawn(ask_forever);
+        SCHEDULER.with_borrow_mut(|scheduler| {
+            scheduler.step();
+            handle.stop();
+            scheduler.step();
+        });
+        assert!(handle.is_done());
+        assert_eq!(logged(), ["a", "withdrawn"]);
+    }
+}
@@ -1,16 +1,27 @@
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::f32::consts::PI;
 use std::ffi::{c_char, CStr};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock, RwLockWriteGuard};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::scheduler::{self, TaskHandle};
+use crate::{number_to_string, parse_number, random, List, ScratchValue, ToScratchString, Variable};
+use macroquad::input::utils::{register_input_subscriber, repeat_all_miniquad_input};
+use macroquad::input::{get_char_pressed, get_keys_down, is_key_pressed, is_mouse_button_down, is_mouse_button_pressed, mouse_position, mouse_wheel, KeyCode, MouseButton};
+use macroquad::material::{gl_use_default_material, gl_use_material, load_material, Material, MaterialParams};
 use macroquad::math::Vec2;
+use macroquad::shapes::{draw_circle, draw_line, draw_rectangle, draw_triangle};
+use macroquad::text::{draw_text, measure_text};
 use macroquad::texture::{draw_texture_ex, DrawTextureParams};
 use macroquad::{
-    color, prelude::ImageFormat, texture::Texture2D, window::{clear_background, next_frame}, Window
+    color, prelude::ImageFormat, texture::{Image, Texture2D}, window::{clear_background, next_frame}, Window
 };
+use miniquad::{BlendFactor, BlendState, BlendValue, Equation, PipelineParams, ShaderSource, UniformDesc, UniformType};
 
-fn svg_to_texture(svg_str: &str) -> Texture2D {
+fn svg_to_image(svg_str: &str) -> Image {
     let opt = resvg::usvg::Options::default();
     let tree = resvg::usvg::Tree::from_str(svg_str, &opt).unwrap();
     let pixmap_size = tree.size().to_int_size();
@@ -23,43 +34,385 @@ fn svg_to_texture(svg_str: &str) -> Texture2D {
         &mut pixmap.as_mut(),
     );
     let png = pixmap.encode_png().unwrap();
-    Texture2D::from_file_with_format(&png, Some(ImageFormat::Png))
+    Image::from_file_with_format(&png, Some(ImageFormat::Png)).unwrap()
 }
 
-enum LazyTexture {
-    Loaded(Texture2D),
-    Unloaded(String),
+enum CostumeData {
+    Svg(String),
+    Bitmap(Vec<u8>),
 }
 
-impl LazyTexture {
-    fn get_texture(&mut self) -> &Texture2D {
+impl CostumeData {
+    fn to_image(&self) -> Image {
         match self {
-            Self::Loaded(texture) => texture,
-            Self::Unloaded(svg) => {
-                let texture = svg_to_texture(svg);
-                *self = Self::Loaded(texture);
-                self.get_texture()
-            }
+            Self::Svg(svg) => svg_to_image(svg),
+            Self::Bitmap(bytes) => Image::from_file_with_format(bytes, None).unwrap(),
+        }
+    }
+}
+
+const EFFECT_VERTEX_SHADER: &str = r#"#version 100
+attribute vec3 position;
+attribute vec2 texcoord;
+attribute vec4 color0;
+
+varying highp vec2 uv;
+varying lowp vec4 color;
+
+uniform mat4 Model;
+uniform mat4 Projection;
+
+void main() {
+    gl_Position = Projection * Model * vec4(position, 1);
+    color = color0 / 255.0;
+    uv = texcoord;
+}
+"#;
+
+// Ported from scratch-render's sprite shader, minus premultiplied alpha which macroquad doesn't use
+const EFFECT_FRAGMENT_SHADER: &str = r#"#version 100
+precision highp float;
+
+varying highp vec2 uv;
+varying lowp vec4 color;
+
+uniform sampler2D Texture;
+uniform float u_color;
+uniform float u_fisheye;
+uniform float u_whirl;
+uniform float u_pixelate;
+uniform float u_mosaic;
+uniform float u_brightness;
+uniform vec2 u_skinSize;
+
+const float epsilon = 1e-3;
+const vec2 kCenter = vec2(0.5, 0.5);
+
+vec3 convertRGB2HSV(vec3 rgb) {
+    const vec4 hueOffsets = vec4(0.0, -1.0 / 3.0, 2.0 / 3.0, -1.0);
+    vec4 temp1 = rgb.b > rgb.g ? vec4(rgb.bg, hueOffsets.wz) : vec4(rgb.gb, hueOffsets.xy);
+    vec4 temp2 = rgb.r > temp1.x ? vec4(rgb.r, temp1.yzx) : vec4(temp1.xyw, rgb.r);
+    float m = min(temp2.y, temp2.w);
+    float C = temp2.x - m;
+    float V = temp2.x;
+    return vec3(abs(temp2.z + (temp2.w - temp2.y) / (6.0 * C + epsilon)), C / (temp2.x + epsilon), V);
+}
+
+vec3 convertHue2RGB(float hue) {
+    float r = abs(hue * 6.0 - 3.0) - 1.0;
+    float g = 2.0 - abs(hue * 6.0 - 2.0);
+    float b = 2.0 - abs(hue * 6.0 - 4.0);
+    return clamp(vec3(r, g, b), 0.0, 1.0);
+}
+
+vec3 convertHSV2RGB(vec3 hsv) {
+    vec3 rgb = convertHue2RGB(hsv.x);
+    float c = hsv.z * hsv.y;
+    return rgb * c + hsv.z - c;
+}
+
+void main() {
+    vec2 texcoord0 = uv;
+    if (u_mosaic > 1.0) {
+        texcoord0 = fract(u_mosaic * texcoord0);
+    }
+    if (u_pixelate > 0.0) {
+        vec2 pixelTexelSize = u_skinSize / u_pixelate;
+        texcoord0 = (floor(texcoord0 * pixelTexelSize) + kCenter) / pixelTexelSize;
+    }
+    if (u_whirl != 0.0) {
+        const float kRadius = 0.5;
+        vec2 offset = texcoord0 - kCenter;
+        float offsetMagnitude = length(offset);
+        float whirlFactor = max(1.0 - (offsetMagnitude / kRadius), 0.0);
+        float whirlActual = u_whirl * whirlFactor * whirlFactor;
+        float sinWhirl = sin(whirlActual);
+        float cosWhirl = cos(whirlActual);
+        mat2 rotationMatrix = mat2(cosWhirl, -sinWhirl, sinWhirl, cosWhirl);
+        texcoord0 = rotationMatrix * offset + kCenter;
+    }
+    if (u_fisheye != 1.0) {
+        vec2 vec = (texcoord0 - kCenter) / kCenter;
+        float vecLength = length(vec);
+        if (vecLength > 0.0) {
+            float r = pow(min(vecLength, 1.0), u_fisheye) * max(1.0, vecLength);
+            vec2 unit = vec / vecLength;
+            texcoord0 = kCenter + r * unit * kCenter;
+        }
+    }
+    vec4 pixel = texture2D(Texture, texcoord0);
+    if (u_color != 0.0) {
+        vec3 hsv = convertRGB2HSV(pixel.rgb);
+        // Grays are nudged to a slightly saturated red so that a hue shift stays visible
+        const float minLightness = 0.11 / 2.0;
+        const float minSaturation = 0.09;
+        if (hsv.z < minLightness) hsv = vec3(0.0, 1.0, minLightness);
+        else if (hsv.y < minSaturation) hsv = vec3(0.0, minSaturation, hsv.z);
+        hsv.x = mod(hsv.x + u_color, 1.0);
+        if (hsv.x < 0.0) hsv.x += 1.0;
+        pixel.rgb = convertHSV2RGB(hsv);
+    }
+    pixel.rgb = clamp(pixel.rgb + vec3(u_brightness), vec3(0.0), vec3(1.0));
+    gl_FragColor = color * pixel;
+}
+"#;
+
+thread_local! {
+    // Shaders can only be compiled once the window exists, and only on the thread that draws
+    static EFFECT_MATERIAL: OnceCell<Material> = const { OnceCell::new() };
+}
+
+fn with_effect_material(f: impl FnOnce(&Material)) {
+    EFFECT_MATERIAL.with(|material| {
+        let material = material.get_or_init(|| {
+            load_material(
+                ShaderSource::Glsl { vertex: EFFECT_VERTEX_SHADER, fragment: EFFECT_FRAGMENT_SHADER },
+                MaterialParams {
+                    pipeline_params: PipelineParams {
+                        color_blend: Some(BlendState::new(
+                            Equation::Add,
+                            BlendFactor::Value(BlendValue::SourceAlpha),
+                            BlendFactor::OneMinusValue(BlendValue::SourceAlpha),
+                        )),
+                        ..Default::default()
+                    },
+                    uniforms: vec![
+                        UniformDesc::new("u_color", UniformType::Float1),
+                        UniformDesc::new("u_fisheye", UniformType::Float1),
+                        UniformDesc::new("u_whirl", UniformType::Float1),
+                        UniformDesc::new("u_pixelate", UniformType::Float1),
+                        UniformDesc::new("u_mosaic", UniformType::Float1),
+                        UniformDesc::new("u_brightness", UniformType::Float1),
+                        UniformDesc::new("u_skinSize", UniformType::Float2),
+                    ],
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        });
+        f(material)
+    })
+}
+
+#[derive(Copy, Clone)]
+pub enum GraphicEffect {
+    Color,
+    Fisheye,
+    Whirl,
+    Pixelate,
+    Mosaic,
+    Brightness,
+    Ghost,
+}
+
+impl GraphicEffect {
+    fn from_i32(i: i32) -> Self {
+        match i {
+            0 => Self::Color,
+            1 => Self::Fisheye,
+            2 => Self::Whirl,
+            3 => Self::Pixelate,
+            4 => Self::Mosaic,
+            5 => Self::Brightness,
+            6 => Self::Ghost,
+            _ => panic!("Invalid graphic effect"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Effects {
+    color: f32,
+    fisheye: f32,
+    whirl: f32,
+    pixelate: f32,
+    mosaic: f32,
+    brightness: f32,
+    ghost: f32,
+}
+
+impl Effects {
+    fn get(&self, effect: GraphicEffect) -> f32 {
+        match effect {
+            GraphicEffect::Color => self.color,
+            GraphicEffect::Fisheye => self.fisheye,
+            GraphicEffect::Whirl => self.whirl,
+            GraphicEffect::Pixelate => self.pixelate,
+            GraphicEffect::Mosaic => self.mosaic,
+            GraphicEffect::Brightness => self.brightness,
+            GraphicEffect::Ghost => self.ghost,
+        }
+    }
+    fn set(&mut self, effect: GraphicEffect, value: f32) {
+        match effect {
+            GraphicEffect::Color => self.color = value,
+            GraphicEffect::Fisheye => self.fisheye = value,
+            GraphicEffect::Whirl => self.whirl = value,
+            GraphicEffect::Pixelate => self.pixelate = value,
+            GraphicEffect::Mosaic => self.mosaic = value,
+            GraphicEffect::Brightness => self.brightness = value.clamp(-100.0, 100.0),
+            GraphicEffect::Ghost => self.ghost = value.clamp(0.0, 100.0),
+        }
+    }
+    fn needs_shader(&self) -> bool {
+        [self.color, self.fisheye, self.whirl, self.pixelate, self.mosaic, self.brightness]
+            .iter()
+            .any(|value| *value != 0.0)
+    }
+    /// Converts the effect values into the uniforms scratch-render feeds its shader.
+    fn apply_uniforms(&self, material: &Material, skin_size: (f32, f32)) {
+        material.set_uniform("u_color", (self.color / 200.0) % 1.0);
+        material.set_uniform("u_fisheye", ((self.fisheye + 100.0) / 100.0).max(0.0));
+        material.set_uniform("u_whirl", -self.whirl * PI / 180.0);
+        material.set_uniform("u_pixelate", self.pixelate.abs() / 10.0);
+        material.set_uniform("u_mosaic", ((self.mosaic.abs() + 10.0) / 10.0).round().clamp(1.0, 512.0));
+        material.set_uniform("u_brightness", self.brightness / 100.0);
+        material.set_uniform("u_skinSize", [skin_size.0, skin_size.1]);
+    }
+    /// Applies the color, brightness and ghost effects to a single pixel, mirroring the effect shader.
+    fn transform_color(&self, [r, g, b, a]: [u8; 4]) -> [u8; 4] {
+        let mut rgb = [r, g, b].map(|c| c as f32 / 255.0);
+        if self.color != 0.0 {
+            let [hue, saturation, value] = rgb_to_hsv(rgb);
+            let (saturation, value) = if value < 0.11 / 2.0 {
+                (1.0, 0.11 / 2.0)
+            } else {
+                (saturation.max(0.09), value)
+            };
+            let hue = (hue + (self.color / 200.0) % 1.0).rem_euclid(1.0);
+            rgb = hsv_to_rgb([hue, saturation, value]);
         }
+        let rgb = rgb.map(|c| ((c + self.brightness / 100.0).clamp(0.0, 1.0) * 255.0).round() as u8);
+        let alpha = (a as f32 * (1.0 - self.ghost / 100.0)).round() as u8;
+        [rgb[0], rgb[1], rgb[2], alpha]
+    }
+    /// Ghost is applied by tinting, so it also works without the effect shader.
+    fn tint(&self) -> color::Color {
+        color::Color::new(1.0, 1.0, 1.0, 1.0 - self.ghost / 100.0)
     }
 }
 
+fn rgb_to_hsv([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    let hue = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / chroma).rem_euclid(6.0) / 6.0
+    } else if max == g {
+        ((b - r) / chroma + 2.0) / 6.0
+    } else {
+        ((r - g) / chroma + 4.0) / 6.0
+    };
+    let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+    [hue, saturation, max]
+}
+
+fn hsv_to_rgb([hue, saturation, value]: [f32; 3]) -> [f32; 3] {
+    let r = (hue * 6.0 - 3.0).abs() - 1.0;
+    let g = 2.0 - (hue * 6.0 - 2.0).abs();
+    let b = 2.0 - (hue * 6.0 - 4.0).abs();
+    let chroma = value * saturation;
+    [r, g, b].map(|c| c.clamp(0.0, 1.0) * chroma + value - chroma)
+}
+
 fn norm_angle(angle: f32) -> f32 {
     (angle + 180.0).rem_euclid(360.0) - 180.0
 }
 
+/// Which pixels of a costume are opaque, so collisions can be checked on the CPU.
+struct AlphaMask {
+    width: usize,
+    height: usize,
+    bitmap_resolution: f32,
+    opaque: Vec<bool>,
+    // Left, top, right and bottom of the opaque pixels in costume units
+    opaque_rect: Option<(f32, f32, f32, f32)>,
+}
+
+impl AlphaMask {
+    fn new(image: &Image, bitmap_resolution: f32) -> Self {
+        let (width, height) = (image.width as usize, image.height as usize);
+        let opaque: Vec<bool> = image.bytes.chunks_exact(4).map(|pixel| pixel[3] > 0).collect();
+        let mut opaque_rect: Option<(usize, usize, usize, usize)> = None;
+        for (i, _) in opaque.iter().enumerate().filter(|(_, opaque)| **opaque) {
+            let (x, y) = (i % width, i / width);
+            opaque_rect = Some(match opaque_rect {
+                None => (x, y, x + 1, y + 1),
+                Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1)),
+            });
+        }
+        let opaque_rect = opaque_rect.map(|(left, top, right, bottom)| {
+            (
+                left as f32 / bitmap_resolution,
+                top as f32 / bitmap_resolution,
+                right as f32 / bitmap_resolution,
+                bottom as f32 / bitmap_resolution,
+            )
+        });
+        Self { width, height, bitmap_resolution, opaque, opaque_rect }
+    }
+
+    /// Looks up a point in costume units, measured from the top left corner.
+    fn is_opaque_at(&self, u: f32, v: f32) -> bool {
+        let (x, y) = ((u * self.bitmap_resolution).floor(), (v * self.bitmap_resolution).floor());
+        if x < 0.0 || y < 0.0 || x >= self.width as f32 || y >= self.height as f32 {
+            return false;
+        }
+        self.opaque[y as usize * self.width + x as usize]
+    }
+}
+
 pub struct Costume {
-    svg: LazyTexture,
+    name: String,
+    data: CostumeData,
+    // Rasterized lazily on the CPU so the costume can be measured without a window
+    image: OnceLock<Arc<Image>>,
+    mask: OnceLock<Arc<AlphaMask>>,
+    texture: Option<Texture2D>,
+    bitmap_resolution: f32,
     rotation_center_x: f32,
     rotation_center_y: f32,
 }
 
 impl Costume {
-    fn new(svg: String, rotation_center_x: i32, rotation_center_y: i32) -> Self {
-        Self { svg: LazyTexture::Unloaded(svg), rotation_center_x: rotation_center_x as f32, rotation_center_y: rotation_center_y as f32 }
+    fn new(data: CostumeData, name: String, bitmap_resolution: f32, rotation_center_x: i32, rotation_center_y: i32) -> Self {
+        // Rotation centers of bitmaps are given in texture pixels, but everything else works in stage units
+        Self {
+            name,
+            data,
+            image: OnceLock::new(),
+            mask: OnceLock::new(),
+            texture: None,
+            bitmap_resolution,
+            rotation_center_x: rotation_center_x as f32 / bitmap_resolution,
+            rotation_center_y: rotation_center_y as f32 / bitmap_resolution,
+        }
+    }
+
+    fn image(&self) -> &Arc<Image> {
+        self.image.get_or_init(|| Arc::new(self.data.to_image()))
+    }
+
+    fn texture(&mut self) -> &Texture2D {
+        if self.texture.is_none() {
+            let texture = Texture2D::from_image(self.image());
+            self.texture = Some(texture);
+        }
+        self.texture.as_ref().unwrap()
+    }
+
+    fn mask(&self) -> Arc<AlphaMask> {
+        self.mask.get_or_init(|| Arc::new(AlphaMask::new(self.image(), self.bitmap_resolution))).clone()
     }
 
-    fn draw(&mut self, x: f32, y: f32, rotation: f32, rotation_style: RotationStyle) {
+    /// Returns the width and height of the costume in stage units.
+    fn size(&self) -> (f32, f32) {
+        let image = self.image();
+        (image.width as f32 / self.bitmap_resolution, image.height as f32 / self.bitmap_resolution)
+    }
+
+    fn draw(&mut self, x: f32, y: f32, scale: f32, rotation: f32, rotation_style: RotationStyle, effects: &Effects) {
         let (rotation, flip_x) = match rotation_style {
             RotationStyle::AllAround => ((rotation - 90.) * PI / 180.0, false),
             RotationStyle::LeftRight => (0.0, match norm_angle(rotation) {
@@ -68,26 +421,126 @@ impl Costume {
             }),
             RotationStyle::DontRotate => (0.0, false),
         };
-        draw_texture_ex(self.svg.get_texture(), x, y, color::WHITE, DrawTextureParams {
+        let (width, height) = self.size();
+        let (rotation_center_x, rotation_center_y) = (self.rotation_center_x, self.rotation_center_y);
+        let use_shader = effects.needs_shader();
+        if use_shader {
+            with_effect_material(|material| {
+                effects.apply_uniforms(material, (width, height));
+                gl_use_material(material);
+            });
+        }
+        let pivot = Vec2 {
+            x: rotation_center_x * scale + x,
+            y: rotation_center_y * scale + y,
+        };
+        // macroquad mirrors within the destination rectangle, but Scratch mirrors around the rotation center
+        let x = if flip_x { x + (2.0 * rotation_center_x - width) * scale } else { x };
+        draw_texture_ex(self.texture(), x, y, effects.tint(), DrawTextureParams {
+            dest_size: Some(Vec2::new(width * scale, height * scale)),
             rotation,
-            pivot: Some(Vec2 {
-                x: self.rotation_center_x + x,
-                y: self.rotation_center_y + y,
-
-            }),
+            pivot: Some(pivot),
             flip_x,
             ..Default::default()
-        })
+        });
+        if use_shader {
+            gl_use_default_material();
+        }
     }
 }
 
 #[no_mangle]
-pub fn new_costume(svg_str: *const c_char, x: i32, y: i32) -> *const Costume {
+pub fn new_costume(svg_str: *const c_char, name: *const c_char, x: i32, y: i32) -> *const Costume {
     let svg_str = unsafe { CStr::from_ptr(svg_str).to_str().unwrap().to_owned() };
-    let costume = Costume::new(svg_str, x, y);
+    let name = unsafe { CStr::from_ptr(name).to_str().unwrap().to_owned() };
+    let costume = Costume::new(CostumeData::Svg(svg_str), name, 1.0, x, y);
     Box::into_raw(Box::new(costume))
 }
 
+#[no_mangle]
+pub fn new_bitmap_costume(data: *const u8, len: usize, name: *const c_char, bitmap_resolution: i32, x: i32, y: i32) -> *const Costume {
+    let data = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+    let name = unsafe { CStr::from_ptr(name).to_str().unwrap().to_owned() };
+    let costume = Costume::new(CostumeData::Bitmap(data), name, bitmap_resolution as f32, x, y);
+    Box::into_raw(Box::new(costume))
+}
+
+/// Wraps a zero-based costume index into range the way Scratch's `setCostume` does.
+fn wrap_costume_index(index: f64, count: usize) -> usize {
+    // Scratch rounds half up and maps non-finite indices to the first costume
+    let index = (index + 0.5).floor();
+    let index = if index.is_finite() { index } else { 0.0 };
+    index.rem_euclid(count as f64) as usize
+}
+
+/// Resolves a costume name, a "next"/"previous" menu item or a numeric string to a costume index.
+fn find_costume(costumes: &[Costume], current: usize, requested: &str, kind: &str) -> Option<usize> {
+    if costumes.is_empty() {
+        return None;
+    }
+    if let Some(index) = costumes.iter().position(|costume| costume.name == requested) {
+        return Some(index);
+    }
+    if requested == format!("next {}", kind) {
+        Some(wrap_costume_index(current as f64 + 1.0, costumes.len()))
+    } else if requested == format!("previous {}", kind) {
+        Some(wrap_costume_index(current as f64 - 1.0, costumes.len()))
+    } else if requested.trim().is_empty() {
+        None
+    } else {
+        parse_number(requested).map(|index| wrap_costume_index(index - 1.0, costumes.len()))
+    }
+}
+
+/// A script started by a hat block.
+struct Hat {
+    callback: extern "C" fn(),
+    running: Arc<AtomicBool>,
+    task: Mutex<Option<Arc<TaskHandle>>>,
+}
+
+impl Hat {
+    fn new(callback: extern "C" fn()) -> Self {
+        Self { callback, running: Arc::new(AtomicBool::new(false)), task: Mutex::new(None) }
+    }
+
+    /// Starts the script unless the previous run is still going, as a task if the project uses the
+    /// scheduler and on its own thread otherwise.
+    fn start(&self) {
+        if scheduler::hats_are_tasks() {
+            let mut task = self.task.lock().unwrap();
+            if task.as_ref().is_some_and(|task| !task.is_done()) {
+                return;
+            }
+            *task = Some(scheduler::spawn(self.callback));
+            return;
+        }
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let callback = self.callback;
+        let running = self.running.clone();
+        thread::spawn(move || {
+            callback();
+            running.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Starts the script over even if it's still running, like Scratch's click hats. A script on its
+    /// own thread can't be stopped, so without the scheduler a running one is left to finish instead.
+    fn restart(&self) {
+        if !scheduler::hats_are_tasks() {
+            self.start();
+            return;
+        }
+        let mut task = self.task.lock().unwrap();
+        if let Some(task) = task.take() {
+            task.stop();
+        }
+        *task = Some(scheduler::spawn(self.callback));
+    }
+}
+
 enum Position {
     Constant(f32, f32),
     Glide {
@@ -141,21 +594,416 @@ impl RotationStyle {
     }
 }
 
+/// Axis-aligned bounds in Scratch stage coordinates.
+#[derive(Copy, Clone)]
+struct Bounds {
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+}
+
+impl Bounds {
+    /// Bounds of a rectangle in costume units (left, top, right, bottom) after placing it on the stage.
+    fn around(transform: &SpriteTransform, (left, top, right, bottom): (f32, f32, f32, f32)) -> Self {
+        let corners = [(left, top), (right, top), (left, bottom), (right, bottom)].map(|(u, v)| transform.to_stage(u, v));
+        Self {
+            left: corners.iter().map(|c| c.0).fold(f32::INFINITY, f32::min),
+            right: corners.iter().map(|c| c.0).fold(f32::NEG_INFINITY, f32::max),
+            bottom: corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min),
+            top: corners.iter().map(|c| c.1).fold(f32::NEG_INFINITY, f32::max),
+        }
+    }
+
+    fn intersects(&self, other: &Bounds) -> bool {
+        self.left <= other.right && other.left <= self.right && self.bottom <= other.top && other.bottom <= self.top
+    }
+}
+
+/// Places costume pixels on the stage the same way sprites are drawn.
+struct SpriteTransform {
+    x: f32,
+    y: f32,
+    scale: f32,
+    sin: f32,
+    cos: f32,
+    flip_x: bool,
+    rotation_center_x: f32,
+    rotation_center_y: f32,
+}
+
+impl SpriteTransform {
+    fn new(sprite: &Sprite, costume: &Costume) -> Self {
+        let (x, y) = sprite.position.get_position();
+        let (rotation, flip_x) = match sprite.rotation_style {
+            RotationStyle::AllAround => ((sprite.direction - 90.).to_radians(), false),
+            RotationStyle::LeftRight => (0.0, norm_angle(sprite.direction) < 0.0),
+            RotationStyle::DontRotate => (0.0, false),
+        };
+        Self {
+            x,
+            y,
+            scale: sprite.size / 100.0,
+            sin: rotation.sin(),
+            cos: rotation.cos(),
+            flip_x,
+            rotation_center_x: costume.rotation_center_x,
+            rotation_center_y: costume.rotation_center_y,
+        }
+    }
+
+    fn backdrop(costume: &Costume) -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            scale: 1.0,
+            sin: 0.0,
+            cos: 1.0,
+            flip_x: false,
+            rotation_center_x: costume.rotation_center_x,
+            rotation_center_y: costume.rotation_center_y,
+        }
+    }
+
+    /// Maps a point in costume units, measured from the top left corner, to stage coordinates.
+    fn to_stage(&self, u: f32, v: f32) -> (f32, f32) {
+        let dx = (u - self.rotation_center_x) * self.scale;
+        let dy = (v - self.rotation_center_y) * self.scale;
+        let dx = if self.flip_x { -dx } else { dx };
+        // Screen space rotation, with y pointing down
+        (self.x + dx * self.cos - dy * self.sin, self.y - (dx * self.sin + dy * self.cos))
+    }
+
+    /// The inverse of `to_stage`.
+    fn to_costume(&self, x: f32, y: f32) -> (f32, f32) {
+        let (sx, sy) = (x - self.x, self.y - y);
+        let dx = sx * self.cos + sy * self.sin;
+        let dy = -sx * self.sin + sy * self.cos;
+        let dx = if self.flip_x { -dx } else { dx };
+        (dx / self.scale + self.rotation_center_x, dy / self.scale + self.rotation_center_y)
+    }
+}
+
+/// Where a sprite's pixels are on the stage. Holds no locks, so several sprites can be compared safely.
+struct Silhouette {
+    transform: SpriteTransform,
+    mask: Arc<AlphaMask>,
+    image: Arc<Image>,
+    bitmap_resolution: f32,
+    effects: Effects,
+}
+
+impl Silhouette {
+    fn new(transform: SpriteTransform, costume: &Costume, effects: Effects) -> Self {
+        Self {
+            transform,
+            mask: costume.mask(),
+            image: costume.image().clone(),
+            bitmap_resolution: costume.bitmap_resolution,
+            effects,
+        }
+    }
+
+    /// The drawn color at a stage point, with straight alpha. Transparent outside of the costume.
+    fn color_at(&self, x: f32, y: f32) -> [u8; 4] {
+        let (u, v) = self.transform.to_costume(x, y);
+        let (px, py) = ((u * self.bitmap_resolution).floor(), (v * self.bitmap_resolution).floor());
+        if px < 0.0 || py < 0.0 || px >= self.image.width as f32 || py >= self.image.height as f32 {
+            return [0; 4];
+        }
+        let index = (py as usize * self.image.width as usize + px as usize) * 4;
+        let pixel = &self.image.bytes[index..index + 4];
+        self.effects.transform_color([pixel[0], pixel[1], pixel[2], pixel[3]])
+    }
+
+    fn contains_point(&self, x: f32, y: f32) -> bool {
+        let (u, v) = self.transform.to_costume(x, y);
+        self.mask.is_opaque_at(u, v)
+    }
+
+    /// Bounds of the opaque pixels, or `None` if the costume is fully transparent.
+    fn bounds(&self) -> Option<Bounds> {
+        self.mask.opaque_rect.map(|rect| Bounds::around(&self.transform, rect))
+    }
+
+    /// Checks every stage pixel where both bounds overlap, like Scratch's CPU collision path.
+    fn touches(&self, other: &Silhouette) -> bool {
+        let (Some(a), Some(b)) = (self.bounds(), other.bounds()) else {
+            return false;
+        };
+        let (left, right) = (a.left.max(b.left).floor() as i32, a.right.min(b.right).ceil() as i32);
+        let (bottom, top) = (a.bottom.max(b.bottom).floor() as i32, a.top.min(b.top).ceil() as i32);
+        (left..=right).any(|x| {
+            (bottom..=top).any(|y| self.contains_point(x as f32, y as f32) && other.contains_point(x as f32, y as f32))
+        })
+    }
+
+    fn touches_edge(&self) -> bool {
+        self.bounds()
+            .is_some_and(|bounds| bounds.left < -240.0 || bounds.right > 240.0 || bounds.bottom < -180.0 || bounds.top > 180.0)
+    }
+}
+
+fn rgb_from_f64(color: f64) -> [u8; 3] {
+    let color = color as i64;
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+/// Scratch's tolerance for "touching color": the top 5 bits of red and green and the top 4 bits of blue.
+fn color_matches(a: [u8; 3], b: [u8; 3]) -> bool {
+    (a[0] & 0b11111000) == (b[0] & 0b11111000) && (a[1] & 0b11111000) == (b[1] & 0b11111000) && (a[2] & 0b11110000) == (b[2] & 0b11110000)
+}
+
+/// The stricter tolerance Scratch uses for the sprite's own color in "color is touching color".
+fn mask_matches(a: [u8; 3], b: [u8; 3]) -> bool {
+    a.iter().zip(b.iter()).all(|(a, b)| (a & 0b11111100) == (b & 0b11111100))
+}
+
+/// Blends layers, given front to back, on top of the white stage like scratch-render's `sampleColor3b`.
+fn sample_stage_color(layers: &[Silhouette], x: f32, y: f32) -> [u8; 3] {
+    let mut color = [0.0f32; 3];
+    let mut blend_alpha = 1.0;
+    for layer in layers {
+        if blend_alpha == 0.0 {
+            break;
+        }
+        let [r, g, b, a] = layer.color_at(x, y);
+        let alpha = a as f32 / 255.0;
+        for (channel, value) in color.iter_mut().zip([r, g, b]) {
+            *channel += value as f32 * alpha * blend_alpha;
+        }
+        blend_alpha *= 1.0 - alpha;
+    }
+    color.map(|channel| (channel + blend_alpha * 255.0).round().min(255.0) as u8)
+}
+
+const BUBBLE_MAX_LINE_WIDTH: f32 = 170.0;
+const BUBBLE_MIN_WIDTH: f32 = 50.0;
+const BUBBLE_PADDING: f32 = 10.0;
+const BUBBLE_CORNER_RADIUS: f32 = 16.0;
+const BUBBLE_STROKE_WIDTH: f32 = 2.0;
+const BUBBLE_TAIL_HEIGHT: f32 = 12.0;
+const BUBBLE_FONT_SIZE: u16 = 14;
+const BUBBLE_LINE_HEIGHT: f32 = 16.0;
+const BUBBLE_MAX_LENGTH: usize = 330;
+
+static NEXT_BUBBLE_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, PartialEq)]
+enum BubbleKind {
+    Say,
+    Think,
+}
+
+struct Bubble {
+    kind: BubbleKind,
+    text: String,
+    // Lets timed bubbles check they haven't been replaced before clearing themselves
+    id: u64,
+    on_sprite_right: bool,
+}
+
+fn text_width(text: &str) -> f32 {
+    measure_text(text, None, BUBBLE_FONT_SIZE, 1.0).width
+}
+
+/// Breaks text into lines at spaces, splitting words that don't fit on a line of their own.
+fn wrap_text(text: &str, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let candidate = if line.is_empty() { word.to_owned() } else { format!("{} {}", line, word) };
+            if text_width(&candidate) <= max_width {
+                line = candidate;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            for c in word.chars() {
+                line.push(c);
+                if line.chars().count() > 1 && text_width(&line) > max_width {
+                    line.pop();
+                    lines.push(std::mem::replace(&mut line, c.to_string()));
+                }
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn draw_rounded_rectangle(x: f32, y: f32, width: f32, height: f32, radius: f32, color: color::Color) {
+    draw_rectangle(x + radius, y, width - 2.0 * radius, height, color);
+    draw_rectangle(x, y + radius, width, height - 2.0 * radius, color);
+    for (cx, cy) in [
+        (x + radius, y + radius),
+        (x + width - radius, y + radius),
+        (x + radius, y + height - radius),
+        (x + width - radius, y + height - radius),
+    ] {
+        draw_circle(cx, cy, radius, color);
+    }
+}
+
+impl Bubble {
+    fn new(kind: BubbleKind, text: &str) -> Self {
+        Self {
+            kind,
+            text: text.chars().take(BUBBLE_MAX_LENGTH).collect(),
+            id: NEXT_BUBBLE_ID.fetch_add(1, Ordering::SeqCst),
+            on_sprite_right: true,
+        }
+    }
+
+    /// Draws the bubble above the sprite, flipping sides when it would leave the stage.
+    fn draw(&mut self, sprite_bounds: Bounds) {
+        let lines = wrap_text(&self.text, BUBBLE_MAX_LINE_WIDTH);
+        let max_line_width = lines.iter().map(|line| text_width(line)).fold(0.0, f32::max);
+        let width = max_line_width.max(BUBBLE_MIN_WIDTH) + 2.0 * BUBBLE_PADDING;
+        let box_height = lines.len() as f32 * BUBBLE_LINE_HEIGHT + 2.0 * BUBBLE_PADDING;
+        let height = box_height + BUBBLE_TAIL_HEIGHT;
+
+        // Only flip if the bubble fits on the other side
+        if self.on_sprite_right && sprite_bounds.right + width > 240.0 && sprite_bounds.left - width > -240.0 {
+            self.on_sprite_right = false;
+        } else if !self.on_sprite_right && sprite_bounds.left - width < -240.0 && sprite_bounds.right + width < 240.0 {
+            self.on_sprite_right = true;
+        }
+        let left = if self.on_sprite_right {
+            sprite_bounds.right.min(240.0 - width).max(-240.0)
+        } else {
+            (sprite_bounds.left - width).max(-240.0).min(240.0 - width)
+        };
+        let top = (sprite_bounds.top + height).min(180.0);
+        let (x, y) = (240.0 + left, 180.0 - top);
+
+        let fill = color::WHITE;
+        let stroke = color::Color::from_rgba(217, 217, 217, 255);
+        let tail_x = if self.on_sprite_right { x + BUBBLE_CORNER_RADIUS } else { x + width - BUBBLE_CORNER_RADIUS };
+        let tail_direction = if self.on_sprite_right { -1.0 } else { 1.0 };
+        let tail_y = y + box_height;
+        match self.kind {
+            BubbleKind::Say => {
+                let inner = Vec2::new(tail_x, tail_y - BUBBLE_STROKE_WIDTH);
+                let outer = Vec2::new(tail_x - tail_direction * 12.0, tail_y - BUBBLE_STROKE_WIDTH);
+                let tip = Vec2::new(tail_x + tail_direction * 4.0, tail_y + BUBBLE_TAIL_HEIGHT);
+                let outer_outline = outer - Vec2::new(tail_direction * BUBBLE_STROKE_WIDTH, 0.0);
+                let tip_outline = tip + Vec2::new(tail_direction * BUBBLE_STROKE_WIDTH, BUBBLE_STROKE_WIDTH);
+                draw_triangle(inner, outer_outline, tip_outline, stroke);
+                draw_triangle(inner, outer, tip, fill);
+            }
+            BubbleKind::Think => {
+                for (offset, radius) in [(0.0, 5.0), (tail_direction * 6.0, 3.0)] {
+                    let (cx, cy) = (tail_x + offset, tail_y + 2.0 + radius + offset.abs());
+                    draw_circle(cx, cy, radius + BUBBLE_STROKE_WIDTH, stroke);
+                    draw_circle(cx, cy, radius, fill);
+                }
+            }
+        }
+        draw_rounded_rectangle(x, y, width, box_height, BUBBLE_CORNER_RADIUS, stroke);
+        draw_rounded_rectangle(
+            x + BUBBLE_STROKE_WIDTH,
+            y + BUBBLE_STROKE_WIDTH,
+            width - 2.0 * BUBBLE_STROKE_WIDTH,
+            box_height - 2.0 * BUBBLE_STROKE_WIDTH,
+            BUBBLE_CORNER_RADIUS - BUBBLE_STROKE_WIDTH,
+            fill,
+        );
+        let text_color = color::Color::from_rgba(0x57, 0x5E, 0x75, 255);
+        for (i, line) in lines.iter().enumerate() {
+            let line_x = x + (width - text_width(line)) / 2.0;
+            let baseline = y + BUBBLE_PADDING + (i + 1) as f32 * BUBBLE_LINE_HEIGHT - 4.0;
+            draw_text(line, line_x, baseline, BUBBLE_FONT_SIZE as f32, text_color);
+        }
+    }
+}
+
 pub struct Sprite {
     costumes: Vec<Costume>,
     current_costume: usize,
     position: Position,
     direction: f32,
     rotation_style: RotationStyle,
+    size: f32,
+    visible: bool,
+    volume: f32,
+    effects: Effects,
+    bubble: Option<Bubble>,
+    click_hats: Vec<Hat>,
+    // Sprite-local variables by name, so that other sprites can read them; the cells belong to the compiled code
+    variables: HashMap<String, &'static Variable>,
 }
 
 impl Sprite {
     fn draw(&mut self) {
-        let costume = &mut self.costumes[self.current_costume];
+        let Some(costume) = self.costumes.get_mut(self.current_costume) else {
+            return;
+        };
         let (x, y) = self.position.get_position();
-        let x = 240. - costume.rotation_center_x + x;
-        let y = 180. - costume.rotation_center_y - y;
-        costume.draw(x, y, self.direction, self.rotation_style);
+        let scale = self.size / 100.0;
+        let x = 240. - costume.rotation_center_x * scale + x;
+        let y = 180. - costume.rotation_center_y * scale - y;
+        costume.draw(x, y, scale, self.direction, self.rotation_style, &self.effects);
+    }
+    fn bounds(&self) -> Bounds {
+        let Some(costume) = self.costumes.get(self.current_costume) else {
+            let (x, y) = self.position.get_position();
+            return Bounds { left: x, right: x, bottom: y, top: y };
+        };
+        let (width, height) = costume.size();
+        Bounds::around(&SpriteTransform::new(self, costume), (0.0, 0.0, width, height))
+    }
+    /// Returns `None` for hidden sprites, which never touch anything.
+    fn silhouette(&self) -> Option<Silhouette> {
+        let costume = self.costumes.get(self.current_costume)?;
+        if !self.visible {
+            return None;
+        }
+        Some(Silhouette::new(SpriteTransform::new(self, costume), costume, self.effects))
+    }
+    /// Hit-tests a stage point against the current costume's pixels rather than its bounding box.
+    fn contains_point(&self, x: f32, y: f32) -> bool {
+        self.silhouette().is_some_and(|silhouette| silhouette.contains_point(x, y))
+    }
+    fn draw_bubble(&mut self) {
+        let bounds = self.bounds();
+        if let Some(bubble) = &mut self.bubble {
+            bubble.draw(bounds);
+        }
+    }
+    fn set_bubble(&mut self, kind: BubbleKind, text: &str) -> Option<u64> {
+        // Saying nothing clears the bubble
+        self.bubble = (!text.is_empty()).then(|| Bubble::new(kind, text));
+        self.bubble.as_ref().map(|bubble| bubble.id)
+    }
+    fn set_size(&mut self, size: f32) {
+        // Scratch keeps sprites between 5 pixels and 1.5 times the stage
+        let (width, height) = match self.costumes.get(self.current_costume) {
+            Some(costume) => costume.size(),
+            None => {
+                self.size = size;
+                return;
+            }
+        };
+        let min_scale = (5.0 / width).max(5.0 / height).min(1.0);
+        let max_scale = (1.5 * 480.0 / width).min(1.5 * 360.0 / height);
+        self.size = (size / 100.0).clamp(min_scale, max_scale) * 100.0;
+    }
+    fn set_costume(&mut self, index: f64) {
+        if !self.costumes.is_empty() {
+            self.current_costume = wrap_costume_index(index, self.costumes.len());
+        }
+    }
+    fn switch_costume_to(&mut self, requested: &str) {
+        if let Some(index) = find_costume(&self.costumes, self.current_costume, requested, "costume") {
+            self.current_costume = index;
+        }
+    }
+    fn variable(&self, name: &str) -> ScratchValue {
+        // Scratch reports 0 for variables the sprite doesn't have
+        self.variables.get(name).map_or(ScratchValue::Number(0.0), |variable| variable.read().unwrap().clone())
     }
     fn point_towards(&mut self, x: f32, y: f32) {
         let (current_x, current_y) = self.position.get_position();
@@ -167,14 +1015,28 @@ impl Sprite {
 
 pub type WrappedSprite = Arc<RwLock<Sprite>>;
 
+/// Locks a sprite to change how it looks, which asks the scheduler for a redraw.
+fn write_sprite(sprite: &WrappedSprite) -> RwLockWriteGuard<'_, Sprite> {
+    scheduler::request_redraw();
+    sprite.write().unwrap()
+}
+
 #[no_mangle]
-pub fn new_sprite(current_costume: i32, x: f32, y: f32, direction: f32, rotation_style: i32) -> *const WrappedSprite {
+pub fn new_sprite(current_costume: i32, x: f32, y: f32, direction: f32, rotation_style: i32, size: f32, visible: bool) -> *const WrappedSprite {
     let sprite = Sprite {
         costumes: Vec::new(),
-        current_costume: current_costume as usize,
+        // Costumes are added afterwards, so only the lookups can catch an index past the end
+        current_costume: current_costume.max(0) as usize,
         position: Position::Constant(x, y),
         direction,
         rotation_style: RotationStyle::from_i32(rotation_style),
+        size,
+        visible,
+        volume: 100.0,
+        effects: Effects::default(),
+        bubble: None,
+        click_hats: Vec::new(),
+        variables: HashMap::new(),
     };
     let arc = Arc::new(RwLock::new(sprite));
     Box::into_raw(Box::new(arc))
@@ -190,7 +1052,7 @@ pub fn motion_add_costume(sprite: *const WrappedSprite, costume: *mut Costume) {
 #[no_mangle]
 pub fn motion_set_x(sprite: *const WrappedSprite, x: f64) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let (_, y) = sprite.position.get_position();
     sprite.position = Position::Constant(x as f32, y);
 }
@@ -198,7 +1060,7 @@ pub fn motion_set_x(sprite: *const WrappedSprite, x: f64) {
 #[no_mangle]
 pub fn motion_set_y(sprite: *const WrappedSprite, y: f64) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let (x, _) = sprite.position.get_position();
     sprite.position = Position::Constant(x, y as f32);
 }
@@ -206,7 +1068,7 @@ pub fn motion_set_y(sprite: *const WrappedSprite, y: f64) {
 #[no_mangle]
 pub fn motion_change_x(sprite: *const WrappedSprite, dx: f64) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let (x, y) = sprite.position.get_position();
     sprite.position = Position::Constant(x + dx as f32, y);
 }
@@ -214,7 +1076,7 @@ pub fn motion_change_x(sprite: *const WrappedSprite, dx: f64) {
 #[no_mangle]
 pub fn motion_change_y(sprite: *const WrappedSprite, dy: f64) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let (x, y) = sprite.position.get_position();
     sprite.position = Position::Constant(x, y + dy as f32);
 }
@@ -241,19 +1103,19 @@ pub fn motion_get_direction(sprite: *const WrappedSprite) -> f64 {
 #[no_mangle]
 pub fn motion_turn_right(sprite: *const WrappedSprite, degrees: f64) {
     let sprite = unsafe { &*sprite };
-    sprite.write().unwrap().direction += degrees as f32;
+    write_sprite(sprite).direction += degrees as f32;
 }
 
 #[no_mangle]
 pub fn motion_turn_left(sprite: *const WrappedSprite, degrees: f64) {
     let sprite = unsafe { &*sprite };
-    sprite.write().unwrap().direction -= degrees as f32;
+    write_sprite(sprite).direction -= degrees as f32;
 }
 
 #[no_mangle]
 pub fn motion_move_steps(sprite: *const WrappedSprite, steps: f64) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let direction = sprite.direction.to_radians();
     let (x, y) = sprite.position.get_position();
     sprite.position = Position::Constant(x - steps as f32 * direction.cos(), y - steps as f32 * direction.sin());
@@ -264,7 +1126,7 @@ pub fn motion_glide_to_xy(sprite: *const WrappedSprite, x: f64, y: f64, duration
     let duration = Duration::from_secs_f64(duration);
     {
         let sprite = unsafe { &*sprite };
-        let mut sprite = sprite.write().unwrap();
+        let mut sprite = write_sprite(sprite);
         let (start_x, start_y) = sprite.position.get_position();
         sprite.position = Position::Glide {
             start_x,
@@ -275,7 +1137,7 @@ pub fn motion_glide_to_xy(sprite: *const WrappedSprite, x: f64, y: f64, duration
             start_time: Instant::now(),
         };
     };
-    thread::sleep(duration); // switch to sleep_until when stable
+    scheduler::wait(duration);
 }
 
 #[no_mangle]
@@ -298,7 +1160,7 @@ pub fn motion_glide_to_cursor(sprite: *const WrappedSprite, scene: *const Scene,
 }
 
 fn random_position() -> (f32, f32) {
-    (rand::random::<f32>() * 480.0 - 240.0, rand::random::<f32>() * 360.0 - 180.0)
+    (random() as f32 * 480.0 - 240.0, random() as f32 * 360.0 - 180.0)
 }
 
 #[no_mangle]
@@ -311,7 +1173,7 @@ pub fn motion_glide_to_random_position(sprite: *const WrappedSprite, duration: f
 pub fn motion_point_towards_sprite(sprite: *const WrappedSprite, target: *const WrappedSprite) {
     let sprite = unsafe { &*sprite };
     let target = unsafe { &*target };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let target = target.read().unwrap();
     let (target_x, target_y) = target.position.get_position();
     sprite.point_towards(target_x, target_y);
@@ -320,7 +1182,7 @@ pub fn motion_point_towards_sprite(sprite: *const WrappedSprite, target: *const
 #[no_mangle]
 pub fn motion_point_towards_cursor(sprite: *const WrappedSprite, scene: *const Scene) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let cursor = unsafe { &*scene }.cursor.read().unwrap();
     sprite.point_towards(cursor.0, 180.0);
 }
@@ -328,7 +1190,7 @@ pub fn motion_point_towards_cursor(sprite: *const WrappedSprite, scene: *const S
 #[no_mangle]
 pub fn motion_go_to_random_position(sprite: *const WrappedSprite) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let (x, y) = random_position();
     sprite.position = Position::Constant(x, y);
 }
@@ -337,7 +1199,7 @@ pub fn motion_go_to_random_position(sprite: *const WrappedSprite) {
 pub fn motion_go_to_sprite(sprite: *const WrappedSprite, target: *const WrappedSprite) {
     let sprite = unsafe { &*sprite };
     let target = unsafe { &*target };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let target = target.read().unwrap();
     let (target_x, target_y) = target.position.get_position();
     sprite.position = Position::Constant(target_x, target_y);
@@ -346,7 +1208,7 @@ pub fn motion_go_to_sprite(sprite: *const WrappedSprite, target: *const WrappedS
 #[no_mangle]
 pub fn motion_go_to_cursor(sprite: *const WrappedSprite, scene: *const Scene) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let cursor = unsafe { &*scene }.cursor.read().unwrap();
     sprite.position = Position::Constant(cursor.0 - 240.0, 180.0 - cursor.1);
 }
@@ -354,7 +1216,7 @@ pub fn motion_go_to_cursor(sprite: *const WrappedSprite, scene: *const Scene) {
 #[no_mangle]
 pub fn motion_if_on_edge_bounce(sprite: *const WrappedSprite) {
     let sprite = unsafe { &*sprite };
-    let mut sprite = sprite.write().unwrap();
+    let mut sprite = write_sprite(sprite);
     let (x, y) = sprite.position.get_position();
     let (rotation_center_x, rotation_center_y) = {
         let costume = &sprite.costumes[sprite.current_costume];
@@ -372,57 +1234,1466 @@ pub fn motion_if_on_edge_bounce(sprite: *const WrappedSprite) {
 #[no_mangle]
 pub fn motion_set_rotation_style(sprite: *const WrappedSprite, rotation_style: i32) {
     let sprite = unsafe { &*sprite };
-    sprite.write().unwrap().rotation_style = RotationStyle::from_i32(rotation_style);
+    write_sprite(sprite).rotation_style = RotationStyle::from_i32(rotation_style);
 }
 
-pub struct Scene {
-    sprites: Vec<WrappedSprite>,
-    cursor: RwLock<(f32, f32)>,
+#[no_mangle]
+pub fn looks_show(sprite: *const WrappedSprite) {
+    let sprite = unsafe { &*sprite };
+    write_sprite(sprite).visible = true;
 }
 
-impl Scene {
-    fn draw(&self) {
-        for sprite in self.sprites.iter() {
-            sprite.write().unwrap().draw();
-        }
-        *self.cursor.write().unwrap() = {
-            let cursor = macroquad::input::mouse_position();
-            (cursor.0 - 240.0, 180.0 - cursor.1)
-        };
+#[no_mangle]
+pub fn looks_hide(sprite: *const WrappedSprite) {
+    let sprite = unsafe { &*sprite };
+    write_sprite(sprite).visible = false;
+}
+
+fn show_bubble_for_secs(sprite: *const WrappedSprite, kind: BubbleKind, message: *const String, secs: f64) {
+    let sprite = unsafe { &*sprite };
+    let message = unsafe { &*message };
+    let id = write_sprite(sprite).set_bubble(kind, message);
+    scheduler::wait(Duration::from_secs_f64(secs.max(0.0)));
+    let mut sprite = write_sprite(sprite);
+    if id.is_some() && sprite.bubble.as_ref().map(|bubble| bubble.id) == id {
+        sprite.bubble = None;
     }
 }
 
 #[no_mangle]
-pub fn new_scene() -> *const Scene {
-    Box::into_raw(Box::new(Scene { sprites: Vec::new(), cursor: RwLock::new((0., 0.))}))
+pub fn looks_say(sprite: *const WrappedSprite, message: *const String) {
+    let sprite = unsafe { &*sprite };
+    let message = unsafe { &*message };
+    write_sprite(sprite).set_bubble(BubbleKind::Say, message);
 }
 
 #[no_mangle]
-pub fn scene_add_sprite(scene: *mut Scene, sprite: *const WrappedSprite) {
-    let scene = unsafe { &mut *scene };
+pub fn looks_think(sprite: *const WrappedSprite, message: *const String) {
     let sprite = unsafe { &*sprite };
-    scene.sprites.push(sprite.clone());
+    let message = unsafe { &*message };
+    write_sprite(sprite).set_bubble(BubbleKind::Think, message);
 }
 
 #[no_mangle]
-pub fn create_window(scene: *const Scene) {
-    let scene = unsafe { &*scene };
-    Window::from_config(macroquad::conf::Conf {
-        miniquad_conf: miniquad::conf::Conf {
-            window_title: "Scratch".to_owned(),
-            window_width: 480,
-            window_height: 360,
-            high_dpi: true,
-            ..Default::default()
-        },
-        ..Default::default()
-    }, window_loop(scene));
+pub fn looks_say_for_secs(sprite: *const WrappedSprite, message: *const String, secs: f64) {
+    show_bubble_for_secs(sprite, BubbleKind::Say, message, secs);
 }
 
-async fn window_loop(scene: &Scene) {
-    loop {
-        clear_background(color::WHITE);
-        scene.draw();
-        next_frame().await
+#[no_mangle]
+pub fn looks_think_for_secs(sprite: *const WrappedSprite, message: *const String, secs: f64) {
+    show_bubble_for_secs(sprite, BubbleKind::Think, message, secs);
+}
+
+#[no_mangle]
+pub fn looks_set_effect_to(sprite: *const WrappedSprite, effect: i32, value: f64) {
+    let sprite = unsafe { &*sprite };
+    write_sprite(sprite).effects.set(GraphicEffect::from_i32(effect), value as f32);
+}
+
+#[no_mangle]
+pub fn looks_change_effect_by(sprite: *const WrappedSprite, effect: i32, change: f64) {
+    let sprite = unsafe { &*sprite };
+    let mut sprite = write_sprite(sprite);
+    let effect = GraphicEffect::from_i32(effect);
+    let value = sprite.effects.get(effect) + change as f32;
+    sprite.effects.set(effect, value);
+}
+
+#[no_mangle]
+pub fn looks_clear_graphic_effects(sprite: *const WrappedSprite) {
+    let sprite = unsafe { &*sprite };
+    write_sprite(sprite).effects = Effects::default();
+}
+
+#[no_mangle]
+pub fn looks_set_size_to(sprite: *const WrappedSprite, size: f64) {
+    let sprite = unsafe { &*sprite };
+    write_sprite(sprite).set_size(size as f32);
+}
+
+#[no_mangle]
+pub fn looks_change_size_by(sprite: *const WrappedSprite, change: f64) {
+    let sprite = unsafe { &*sprite };
+    let mut sprite = write_sprite(sprite);
+    let size = sprite.size + change as f32;
+    sprite.set_size(size);
+}
+
+#[no_mangle]
+pub fn looks_get_size(sprite: *const WrappedSprite) -> f64 {
+    let sprite = unsafe { &*sprite };
+    (sprite.read().unwrap().size as f64 + 0.5).floor()
+}
+
+#[no_mangle]
+pub fn looks_switch_costume_to_number(sprite: *const WrappedSprite, costume: f64) {
+    let sprite = unsafe { &*sprite };
+    write_sprite(sprite).set_costume(costume - 1.0);
+}
+
+#[no_mangle]
+pub fn looks_switch_costume_to_name(sprite: *const WrappedSprite, costume: *const String) {
+    let sprite = unsafe { &*sprite };
+    let costume = unsafe { &*costume };
+    write_sprite(sprite).switch_costume_to(costume);
+}
+
+#[no_mangle]
+pub fn looks_next_costume(sprite: *const WrappedSprite) {
+    let sprite = unsafe { &*sprite };
+    let mut sprite = write_sprite(sprite);
+    let next = sprite.current_costume as f64 + 1.0;
+    sprite.set_costume(next);
+}
+
+#[no_mangle]
+pub fn looks_costume_number(sprite: *const WrappedSprite) -> f64 {
+    let sprite = unsafe { &*sprite };
+    sprite.read().unwrap().current_costume as f64 + 1.0
+}
+
+#[no_mangle]
+pub fn looks_costume_name(sprite: *const WrappedSprite) -> *mut String {
+    let sprite = unsafe { &*sprite };
+    let sprite = sprite.read().unwrap();
+    let name = sprite.costumes.get(sprite.current_costume).map(|costume| costume.name.clone()).unwrap_or_default();
+    Box::into_raw(Box::new(name))
+}
+
+pub struct Stage {
+    backdrops: Vec<Costume>,
+    current_backdrop: usize,
+    backdrop_hats: Vec<(String, Hat)>,
+    click_hats: Vec<Hat>,
+}
+
+impl Stage {
+    fn draw(&mut self) {
+        if let Some(backdrop) = self.backdrops.get_mut(self.current_backdrop) {
+            let x = 240. - backdrop.rotation_center_x;
+            let y = 180. - backdrop.rotation_center_y;
+            backdrop.draw(x, y, 1.0, 90., RotationStyle::DontRotate, &Effects::default());
+        }
+    }
+    fn silhouette(&self) -> Option<Silhouette> {
+        let backdrop = self.backdrops.get(self.current_backdrop)?;
+        Some(Silhouette::new(SpriteTransform::backdrop(backdrop), backdrop, Effects::default()))
+    }
+    fn set_backdrop(&mut self, index: usize) {
+        self.current_backdrop = index;
+        scheduler::request_redraw();
+        let name = self.backdrops[index].name.to_lowercase();
+        for (_, hat) in self.backdrop_hats.iter().filter(|(backdrop, _)| backdrop.to_lowercase() == name) {
+            hat.start();
+        }
+    }
+    fn switch_backdrop_to(&mut self, requested: &str) {
+        // A backdrop actually named "random backdrop" wins over the menu item
+        let named = self.backdrops.iter().position(|backdrop| backdrop.name == requested);
+        let index = if named.is_none() && requested == "random backdrop" && !self.backdrops.is_empty() {
+            // Scratch never picks the current backdrop so that the block always has a visible effect
+            match self.backdrops.len() {
+                1 => Some(0),
+                len => Some((self.current_backdrop + 1 + (random() * (len - 1) as f64) as usize) % len),
+            }
+        } else {
+            find_costume(&self.backdrops, self.current_backdrop, requested, "backdrop")
+        };
+        if let Some(index) = index {
+            self.set_backdrop(index);
+        }
+    }
+}
+
+/// Returns the name Scratch uses for a key, or `None` for keys it ignores such as modifiers.
+fn scratch_key_name(key: KeyCode) -> Option<String> {
+    let name = match key {
+        KeyCode::Space => "space",
+        KeyCode::Left => "left arrow",
+        KeyCode::Up => "up arrow",
+        KeyCode::Right => "right arrow",
+        KeyCode::Down => "down arrow",
+        KeyCode::Enter | KeyCode::KpEnter => "enter",
+        KeyCode::Apostrophe => "'",
+        KeyCode::Comma => ",",
+        KeyCode::Minus | KeyCode::KpSubtract => "-",
+        KeyCode::Period | KeyCode::KpDecimal => ".",
+        KeyCode::Slash | KeyCode::KpDivide => "/",
+        KeyCode::Semicolon => ";",
+        KeyCode::Equal | KeyCode::KpEqual => "=",
+        KeyCode::LeftBracket => "[",
+        KeyCode::Backslash => "\\",
+        KeyCode::RightBracket => "]",
+        KeyCode::GraveAccent => "`",
+        KeyCode::KpMultiply => "*",
+        KeyCode::KpAdd => "+",
+        KeyCode::Key0 | KeyCode::Kp0 => "0",
+        KeyCode::Key1 | KeyCode::Kp1 => "1",
+        KeyCode::Key2 | KeyCode::Kp2 => "2",
+        KeyCode::Key3 | KeyCode::Kp3 => "3",
+        KeyCode::Key4 | KeyCode::Kp4 => "4",
+        KeyCode::Key5 | KeyCode::Kp5 => "5",
+        KeyCode::Key6 | KeyCode::Kp6 => "6",
+        KeyCode::Key7 | KeyCode::Kp7 => "7",
+        KeyCode::Key8 | KeyCode::Kp8 => "8",
+        KeyCode::Key9 | KeyCode::Kp9 => "9",
+        // Letters debug-print as themselves
+        key => {
+            let name = format!("{:?}", key);
+            return (name.len() == 1).then_some(name);
+        }
+    };
+    Some(name.to_owned())
+}
+
+/// Normalizes a key argument the way Scratch does: special names stay, anything else becomes its first character, uppercased.
+fn scratch_key(key: &str) -> String {
+    match key {
+        "space" | "left arrow" | "up arrow" | "right arrow" | "down arrow" | "enter" | "any" => key.to_owned(),
+        _ => match key.chars().next() {
+            Some(' ') => "space".to_owned(),
+            Some(c) => c.to_uppercase().collect(),
+            None => String::new(),
+        },
+    }
+}
+
+/// Collects key presses, including the operating system's key repeats, which macroquad only reports as raw events.
+#[derive(Default)]
+struct KeyPresses(Vec<KeyCode>);
+
+impl miniquad::EventHandler for KeyPresses {
+    fn update(&mut self) {}
+    fn draw(&mut self) {}
+    fn key_down_event(&mut self, keycode: KeyCode, _keymods: miniquad::KeyMods, _repeat: bool) {
+        self.0.push(keycode);
+    }
+}
+
+struct Question {
+    id: u64,
+    sprite: Option<WrappedSprite>,
+    text: String,
+}
+
+/// The "ask and wait" text box. Questions asked while another one is open wait in line.
+#[derive(Default)]
+struct Prompt {
+    questions: VecDeque<Question>,
+    input: String,
+    next_id: u64,
+}
+
+const PROMPT_MARGIN: f32 = 8.0;
+const PROMPT_PADDING: f32 = 8.0;
+const PROMPT_INPUT_HEIGHT: f32 = 32.0;
+const PROMPT_BUTTON_RADIUS: f32 = 12.0;
+
+impl Prompt {
+    /// Sprites that ask show the question in their speech bubble, the stage shows it in the prompt.
+    fn show_next_question(&self) {
+        if let Some(Question { sprite: Some(sprite), text, .. }) = self.questions.front() {
+            sprite.write().unwrap().set_bubble(BubbleKind::Say, text);
+        }
+    }
+    fn title(&self) -> Option<&str> {
+        let question = self.questions.front()?;
+        match &question.sprite {
+            Some(sprite) if sprite.read().unwrap().visible => None,
+            _ => Some(&question.text),
+        }
+    }
+    fn submit_button(&self) -> (f32, f32) {
+        let x = 480.0 - PROMPT_MARGIN - PROMPT_PADDING - PROMPT_BUTTON_RADIUS - 4.0;
+        let y = 360.0 - PROMPT_MARGIN - PROMPT_PADDING - PROMPT_INPUT_HEIGHT / 2.0;
+        (x, y)
+    }
+    /// Stores the typed answer once the user submits it, before the question is removed, so that
+    /// nobody waiting on the question can see it gone while the old answer is still there.
+    /// Returns whether a question was answered.
+    fn handle_input(&mut self, answer: &RwLock<String>) -> bool {
+        if self.questions.is_empty() {
+            return false;
+        }
+        while let Some(c) = get_char_pressed() {
+            if !c.is_control() {
+                self.input.push(c);
+            }
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            self.input.pop();
+        }
+        let (button_x, button_y) = self.submit_button();
+        let (mouse_x, mouse_y) = mouse_position();
+        let clicked = is_mouse_button_pressed(MouseButton::Left)
+            && (mouse_x - button_x).hypot(mouse_y - button_y) <= PROMPT_BUTTON_RADIUS;
+        if !clicked && !is_key_pressed(KeyCode::Enter) && !is_key_pressed(KeyCode::KpEnter) {
+            return false;
+        }
+        *answer.write().unwrap() = std::mem::take(&mut self.input);
+        if let Some(Question { sprite: Some(sprite), .. }) = self.questions.pop_front() {
+            sprite.write().unwrap().bubble = None;
+        }
+        self.show_next_question();
+        true
+    }
+    /// Drops the question of a script that was stopped, moving on to the next one if it was being asked.
+    fn withdraw(&mut self, id: u64) {
+        let Some(index) = self.questions.iter().position(|question| question.id == id) else {
+            return;
+        };
+        let question = self.questions.remove(index).unwrap();
+        if index == 0 {
+            if let Some(sprite) = question.sprite {
+                sprite.write().unwrap().bubble = None;
+            }
+            self.input.clear();
+            self.show_next_question();
+        }
+    }
+    /// Returns the x, y, width and height of the prompt box in screen coordinates.
+    fn rect(&self) -> (f32, f32, f32, f32) {
+        let title_height = if self.title().is_some() { BUBBLE_LINE_HEIGHT + PROMPT_PADDING } else { 0.0 };
+        let height = PROMPT_INPUT_HEIGHT + title_height + 2.0 * PROMPT_PADDING;
+        (PROMPT_MARGIN, 360.0 - PROMPT_MARGIN - height, 480.0 - 2.0 * PROMPT_MARGIN, height)
+    }
+    fn contains_point(&self, x: f32, y: f32) -> bool {
+        let (rect_x, rect_y, width, height) = self.rect();
+        !self.questions.is_empty() && x >= rect_x && x <= rect_x + width && y >= rect_y && y <= rect_y + height
+    }
+    fn draw(&self) {
+        if self.questions.is_empty() {
+            return;
+        }
+        let title = self.title();
+        let (x, y, width, height) = self.rect();
+        let title_height = height - PROMPT_INPUT_HEIGHT - 2.0 * PROMPT_PADDING;
+        let border = color::Color::from_rgba(217, 217, 217, 255);
+        let accent = color::Color::from_rgba(0x85, 0x5C, 0xD6, 255);
+        let text_color = color::Color::from_rgba(0x57, 0x5E, 0x75, 255);
+        draw_rounded_rectangle(x, y, width, height, 8.0, border);
+        draw_rounded_rectangle(x + 2.0, y + 2.0, width - 4.0, height - 4.0, 6.0, color::WHITE);
+        if let Some(title) = title {
+            draw_text(title, x + PROMPT_PADDING, y + PROMPT_PADDING + BUBBLE_LINE_HEIGHT - 4.0, BUBBLE_FONT_SIZE as f32, text_color);
+        }
+
+        let input_x = x + PROMPT_PADDING;
+        let input_y = y + PROMPT_PADDING + title_height;
+        let input_width = width - 2.0 * PROMPT_PADDING;
+        draw_rounded_rectangle(input_x, input_y, input_width, PROMPT_INPUT_HEIGHT, PROMPT_INPUT_HEIGHT / 2.0, accent);
+        draw_rounded_rectangle(input_x + 2.0, input_y + 2.0, input_width - 4.0, PROMPT_INPUT_HEIGHT - 4.0, PROMPT_INPUT_HEIGHT / 2.0 - 2.0, color::WHITE);
+        // Keep the end of long answers in view
+        let text_space = input_width - 2.0 * PROMPT_BUTTON_RADIUS - 3.0 * PROMPT_PADDING;
+        let mut visible = self.input.as_str();
+        while text_width(visible) > text_space {
+            let mut chars = visible.chars();
+            chars.next();
+            visible = chars.as_str();
+        }
+        let baseline = input_y + PROMPT_INPUT_HEIGHT / 2.0 + BUBBLE_FONT_SIZE as f32 / 3.0;
+        draw_text(&format!("{}|", visible), input_x + 2.0 * PROMPT_PADDING, baseline, BUBBLE_FONT_SIZE as f32, text_color);
+
+        let (button_x, button_y) = self.submit_button();
+        draw_circle(button_x, button_y, PROMPT_BUTTON_RADIUS, accent);
+        draw_line(button_x - 5.0, button_y, button_x - 1.0, button_y + 4.0, 2.0, color::WHITE);
+        draw_line(button_x - 1.0, button_y + 4.0, button_x + 6.0, button_y - 4.0, 2.0, color::WHITE);
+    }
+}
+
+const MONITOR_FONT_SIZE: u16 = 12;
+const MONITOR_LARGE_FONT_SIZE: u16 = 16;
+const MONITOR_PADDING: f32 = 5.0;
+const MONITOR_ROW_HEIGHT: f32 = 18.0;
+const MONITOR_VALUE_MIN_WIDTH: f32 = 40.0;
+const MONITOR_SLIDER_HEIGHT: f32 = 14.0;
+const LIST_MONITOR_HEADER_HEIGHT: f32 = 20.0;
+const LIST_MONITOR_ROW_HEIGHT: f32 = 22.0;
+
+fn monitor_text_width(text: &str, font_size: u16) -> f32 {
+    measure_text(text, None, font_size, 1.0).width
+}
+
+/// Cuts text down with an ellipsis until it fits in the given width.
+fn fit_text(text: &str, max_width: f32) -> String {
+    if monitor_text_width(text, MONITOR_FONT_SIZE) <= max_width {
+        return text.to_owned();
+    }
+    let mut fitted = text.to_owned();
+    while !fitted.is_empty() && monitor_text_width(&format!("{}…", fitted), MONITOR_FONT_SIZE) > max_width {
+        fitted.pop();
+    }
+    format!("{}…", fitted)
+}
+
+/// Monitors show numbers to at most 6 decimal places.
+fn monitor_value_text(value: &ScratchValue) -> String {
+    match value {
+        ScratchValue::Number(n) if n.is_finite() => number_to_string((n * 1e6).round() / 1e6),
+        value => value.to_scratch_string(),
+    }
+}
+
+#[derive(Copy, Clone)]
+enum MonitorMode {
+    Normal,
+    Large,
+    Slider { min: f64, max: f64 },
+}
+
+impl MonitorMode {
+    fn from_i32(mode: i32, slider_min: f64, slider_max: f64) -> Self {
+        match mode {
+            0 => Self::Normal,
+            1 => Self::Large,
+            2 => Self::Slider { min: slider_min, max: slider_max },
+            _ => panic!("Invalid monitor mode"),
+        }
+    }
+}
+
+enum MonitorSource {
+    Variable { variable: &'static Variable, mode: MonitorMode },
+    /// `scroll` is the first item shown
+    List { list: &'static List, width: f32, height: f32, scroll: usize },
+}
+
+pub struct Monitor {
+    label: String,
+    x: f32,
+    y: f32,
+    visible: bool,
+    source: MonitorSource,
+    dragging_slider: bool,
+}
+
+impl Monitor {
+    /// Returns the x, y, width and height of the monitor in screen coordinates.
+    fn rect(&self) -> (f32, f32, f32, f32) {
+        match &self.source {
+            MonitorSource::Variable { variable, mode } => {
+                let value = monitor_value_text(&variable.read().unwrap());
+                if let MonitorMode::Large = mode {
+                    let width = (monitor_text_width(&value, MONITOR_LARGE_FONT_SIZE) + 2.0 * MONITOR_PADDING).max(MONITOR_VALUE_MIN_WIDTH);
+                    return (self.x, self.y, width, MONITOR_ROW_HEIGHT + 4.0);
+                }
+                let value_width = (monitor_text_width(&value, MONITOR_FONT_SIZE) + 2.0 * MONITOR_PADDING).max(MONITOR_VALUE_MIN_WIDTH);
+                let width = monitor_text_width(&self.label, MONITOR_FONT_SIZE) + value_width + 3.0 * MONITOR_PADDING;
+                let slider_height = if let MonitorMode::Slider { .. } = mode { MONITOR_SLIDER_HEIGHT } else { 0.0 };
+                (self.x, self.y, width, MONITOR_ROW_HEIGHT + slider_height + 2.0 * MONITOR_PADDING)
+            }
+            MonitorSource::List { width, height, .. } => (self.x, self.y, *width, *height),
+        }
+    }
+    fn contains_point(&self, x: f32, y: f32) -> bool {
+        let (rect_x, rect_y, width, height) = self.rect();
+        self.visible && x >= rect_x && x <= rect_x + width && y >= rect_y && y <= rect_y + height
+    }
+    /// Returns the left end, right end and height of the slider track in screen coordinates.
+    fn slider_track(&self) -> Option<(f32, f32, f32)> {
+        let MonitorSource::Variable { mode: MonitorMode::Slider { .. }, .. } = self.source else {
+            return None;
+        };
+        let (x, y, width, height) = self.rect();
+        Some((x + 2.0 * MONITOR_PADDING, x + width - 2.0 * MONITOR_PADDING, y + height - MONITOR_PADDING - MONITOR_SLIDER_HEIGHT / 2.0))
+    }
+    fn list_rows(height: f32) -> usize {
+        ((height - 2.0 * LIST_MONITOR_HEADER_HEIGHT) / LIST_MONITOR_ROW_HEIGHT).floor().max(0.0) as usize
+    }
+    /// Starts dragging the slider if the click landed on it.
+    fn click(&mut self, x: f32, y: f32) {
+        if let Some((left, right, track_y)) = self.slider_track() {
+            if x >= left - 6.0 && x <= right + 6.0 && (y - track_y).abs() <= MONITOR_SLIDER_HEIGHT / 2.0 {
+                self.dragging_slider = true;
+                self.drag_slider(x);
+            }
+        }
+    }
+    fn drag_slider(&mut self, x: f32) {
+        let Some((left, right, _)) = self.slider_track() else {
+            return;
+        };
+        let MonitorSource::Variable { variable, mode: MonitorMode::Slider { min, max } } = self.source else {
+            return;
+        };
+        let progress = ((x - left) / (right - left)).clamp(0.0, 1.0) as f64;
+        // Scratch steps by whole numbers when both ends are whole, and by hundredths otherwise
+        let step = if min.fract() == 0.0 && max.fract() == 0.0 { 1.0 } else { 0.01 };
+        let value = min + ((max - min) * progress / step).round() * step;
+        *variable.write().unwrap() = ScratchValue::Number(value);
+    }
+    fn scroll(&mut self, rows: isize) {
+        if let MonitorSource::List { list, height, scroll, .. } = &mut self.source {
+            let len = list.read().unwrap().len();
+            let max_scroll = len.saturating_sub(Monitor::list_rows(*height));
+            *scroll = scroll.saturating_add_signed(rows).min(max_scroll);
+        }
+    }
+    fn draw(&self) {
+        if !self.visible {
+            return;
+        }
+        let (x, y, width, height) = self.rect();
+        let background = color::Color::from_rgba(0xE5, 0xF0, 0xFF, 255);
+        let border = color::Color::from_rgba(0xC6, 0xCD, 0xD9, 255);
+        let text_color = color::Color::from_rgba(0x57, 0x5E, 0x75, 255);
+        let variable_color = color::Color::from_rgba(0xFF, 0x8C, 0x1A, 255);
+        let list_color = color::Color::from_rgba(0xFC, 0x66, 0x2C, 255);
+        match &self.source {
+            MonitorSource::Variable { variable, mode: MonitorMode::Large } => {
+                let value = monitor_value_text(&variable.read().unwrap());
+                draw_rounded_rectangle(x, y, width, height, 4.0, variable_color);
+                let text_x = x + (width - monitor_text_width(&value, MONITOR_LARGE_FONT_SIZE)) / 2.0;
+                draw_text(&value, text_x, y + height / 2.0 + MONITOR_LARGE_FONT_SIZE as f32 / 3.0, MONITOR_LARGE_FONT_SIZE as f32, color::WHITE);
+            }
+            MonitorSource::Variable { variable, mode } => {
+                let value = monitor_value_text(&variable.read().unwrap());
+                draw_rounded_rectangle(x, y, width, height, 4.0, border);
+                draw_rounded_rectangle(x + 1.0, y + 1.0, width - 2.0, height - 2.0, 3.0, background);
+                let baseline = y + MONITOR_PADDING + MONITOR_ROW_HEIGHT / 2.0 + MONITOR_FONT_SIZE as f32 / 3.0;
+                draw_text(&self.label, x + MONITOR_PADDING, baseline, MONITOR_FONT_SIZE as f32, text_color);
+                let value_x = x + 2.0 * MONITOR_PADDING + monitor_text_width(&self.label, MONITOR_FONT_SIZE);
+                let value_width = x + width - MONITOR_PADDING - value_x;
+                draw_rounded_rectangle(value_x, y + MONITOR_PADDING, value_width, MONITOR_ROW_HEIGHT, 4.0, variable_color);
+                let text_x = value_x + (value_width - monitor_text_width(&value, MONITOR_FONT_SIZE)) / 2.0;
+                draw_text(&value, text_x, baseline, MONITOR_FONT_SIZE as f32, color::WHITE);
+                if let (MonitorMode::Slider { min, max }, Some((left, right, track_y))) = (mode, self.slider_track()) {
+                    draw_line(left, track_y, right, track_y, 4.0, border);
+                    let current = variable.read().unwrap().to_number();
+                    let progress = if max > min { ((current - min) / (max - min)).clamp(0.0, 1.0) as f32 } else { 0.0 };
+                    draw_circle(left + (right - left) * progress, track_y, 6.0, color::Color::from_rgba(0x4C, 0x97, 0xFF, 255));
+                }
+            }
+            MonitorSource::List { list, scroll, .. } => {
+                draw_rounded_rectangle(x, y, width, height, 4.0, border);
+                draw_rounded_rectangle(x + 1.0, y + 1.0, width - 2.0, height - 2.0, 3.0, background);
+                // Header and footer
+                draw_rectangle(x + 1.0, y + 1.0, width - 2.0, LIST_MONITOR_HEADER_HEIGHT - 1.0, color::WHITE);
+                draw_rectangle(x + 1.0, y + height - LIST_MONITOR_HEADER_HEIGHT, width - 2.0, LIST_MONITOR_HEADER_HEIGHT - 1.0, color::WHITE);
+                let centered_baseline = LIST_MONITOR_HEADER_HEIGHT / 2.0 + MONITOR_FONT_SIZE as f32 / 3.0;
+                let label = fit_text(&self.label, width - 2.0 * MONITOR_PADDING);
+                let label_x = x + (width - monitor_text_width(&label, MONITOR_FONT_SIZE)) / 2.0;
+                draw_text(&label, label_x, y + centered_baseline, MONITOR_FONT_SIZE as f32, text_color);
+
+                let items = list.read().unwrap();
+                let length = format!("length {}", items.len());
+                let length_x = x + (width - monitor_text_width(&length, MONITOR_FONT_SIZE)) / 2.0;
+                draw_text(&length, length_x, y + height - LIST_MONITOR_HEADER_HEIGHT + centered_baseline, MONITOR_FONT_SIZE as f32, text_color);
+                if items.is_empty() {
+                    let empty_x = x + (width - monitor_text_width("(empty)", MONITOR_FONT_SIZE)) / 2.0;
+                    draw_text("(empty)", empty_x, y + height / 2.0, MONITOR_FONT_SIZE as f32, text_color);
+                    return;
+                }
+
+                // Every index gets as much room as the widest one so that the items line up
+                let index_width = monitor_text_width(&items.len().to_string(), MONITOR_FONT_SIZE) + MONITOR_PADDING;
+                let item_x = x + MONITOR_PADDING + index_width;
+                let item_width = width - index_width - 2.0 * MONITOR_PADDING;
+                let rows = Monitor::list_rows(height);
+                let first = (*scroll).min(items.len().saturating_sub(rows));
+                for (row, (index, item)) in items.iter().enumerate().skip(first).take(rows).enumerate() {
+                    let row_y = y + LIST_MONITOR_HEADER_HEIGHT + row as f32 * LIST_MONITOR_ROW_HEIGHT;
+                    let baseline = row_y + LIST_MONITOR_ROW_HEIGHT / 2.0 + MONITOR_FONT_SIZE as f32 / 3.0;
+                    draw_text(&(index + 1).to_string(), x + MONITOR_PADDING, baseline, MONITOR_FONT_SIZE as f32, text_color);
+                    draw_rounded_rectangle(item_x, row_y + 2.0, item_width, LIST_MONITOR_ROW_HEIGHT - 4.0, 4.0, list_color);
+                    let text = fit_text(&item.to_scratch_string(), item_width - 2.0 * MONITOR_PADDING);
+                    draw_text(&text, item_x + MONITOR_PADDING, baseline, MONITOR_FONT_SIZE as f32, color::WHITE);
+                }
+            }
+        }
+    }
+}
+
+pub type WrappedMonitor = Arc<RwLock<Monitor>>;
+
+enum Clock {
+    System(Instant),
+    /// Reports seconds; lets the host (or a test) drive the timer by hand
+    External(extern "C" fn() -> f64),
+}
+
+impl Clock {
+    fn now(&self) -> f64 {
+        match self {
+            Self::System(origin) => origin.elapsed().as_secs_f64(),
+            Self::External(clock) => clock(),
+        }
+    }
+}
+
+struct TimerHat {
+    threshold: f64,
+    hat: Hat,
+    was_over: bool,
+}
+
+struct Timer {
+    clock: Clock,
+    start: f64,
+    hats: Vec<TimerHat>,
+}
+
+impl Timer {
+    fn new(clock: Clock) -> Self {
+        let start = clock.now();
+        Self { clock, start, hats: Vec::new() }
+    }
+    fn seconds(&self) -> f64 {
+        self.clock.now() - self.start
+    }
+    fn reset(&mut self) {
+        self.start = self.clock.now();
+    }
+    fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.reset();
+    }
+    /// Hats only fire when the timer goes from not over their threshold to over it.
+    fn start_hats(&mut self) {
+        let seconds = self.seconds();
+        for timer_hat in &mut self.hats {
+            let over = seconds > timer_hat.threshold;
+            if over && !timer_hat.was_over {
+                timer_hat.hat.start();
+            }
+            timer_hat.was_over = over;
+        }
+    }
+}
+
+pub struct Scene {
+    // Back to front; sprite locks are never taken while this one is held
+    sprites: RwLock<Vec<WrappedSprite>>,
+    stage: RwLock<Stage>,
+    cursor: RwLock<(f32, f32)>,
+    // Sprite locks may be taken while this one is held, but not the other way around
+    prompt: Mutex<Prompt>,
+    question_answered: Condvar,
+    answer: RwLock<String>,
+    keys_down: RwLock<HashSet<String>>,
+    key_hats: RwLock<Vec<(String, Hat)>>,
+    mouse_down: AtomicBool,
+    timer: Mutex<Timer>,
+    // Back to front, drawn over the sprites
+    monitors: RwLock<Vec<WrappedMonitor>>,
+}
+
+impl Scene {
+    fn draw(&self) {
+        self.stage.write().unwrap().draw();
+        let sprites = self.sprites.read().unwrap().clone();
+        for sprite in sprites.iter() {
+            let mut sprite = sprite.write().unwrap();
+            if sprite.visible {
+                sprite.draw();
+            }
+        }
+        // Bubbles go on top of every sprite
+        for sprite in sprites.iter() {
+            let mut sprite = sprite.write().unwrap();
+            if sprite.visible {
+                sprite.draw_bubble();
+            }
+        }
+        for monitor in self.monitors.read().unwrap().iter() {
+            monitor.read().unwrap().draw();
+        }
+        self.prompt.lock().unwrap().draw();
+        *self.cursor.write().unwrap() = {
+            let cursor = macroquad::input::mouse_position();
+            (cursor.0 - 240.0, 180.0 - cursor.1)
+        };
+    }
+    fn handle_input(&self, key_presses: KeyPresses) {
+        let (mouse_x, mouse_y) = mouse_position();
+        let (prompt_open, clicked_prompt) = {
+            let mut prompt = self.prompt.lock().unwrap();
+            let clicked_prompt = prompt.contains_point(mouse_x, mouse_y);
+            if prompt.handle_input(&self.answer) {
+                self.question_answered.notify_all();
+            }
+            (!prompt.questions.is_empty(), clicked_prompt)
+        };
+
+        self.mouse_down.store(is_mouse_button_down(MouseButton::Left), Ordering::SeqCst);
+        let clicked_monitor = !clicked_prompt && self.handle_monitor_input(mouse_x, mouse_y);
+        if is_mouse_button_pressed(MouseButton::Left) && !clicked_prompt && !clicked_monitor {
+            self.click(mouse_x - 240.0, 180.0 - mouse_y);
+        }
+
+        // Typing into the prompt doesn't count as pressing keys
+        if prompt_open {
+            self.keys_down.write().unwrap().clear();
+            return;
+        }
+        *self.keys_down.write().unwrap() = get_keys_down().into_iter().filter_map(scratch_key_name).collect();
+        let key_hats = self.key_hats.read().unwrap();
+        for key in key_presses.0.into_iter().filter_map(scratch_key_name) {
+            for (_, hat) in key_hats.iter().filter(|(hat_key, _)| *hat_key == key || hat_key == "any") {
+                hat.start();
+            }
+        }
+    }
+    /// Scrolls lists and drags sliders; returns whether the mouse was pressed on a monitor.
+    fn handle_monitor_input(&self, mouse_x: f32, mouse_y: f32) -> bool {
+        let monitors = self.monitors.read().unwrap();
+        let pressed = is_mouse_button_pressed(MouseButton::Left);
+        let down = is_mouse_button_down(MouseButton::Left);
+        let (_, wheel) = mouse_wheel();
+        for monitor in monitors.iter() {
+            let mut monitor = monitor.write().unwrap();
+            if monitor.dragging_slider {
+                if down {
+                    monitor.drag_slider(mouse_x);
+                } else {
+                    monitor.dragging_slider = false;
+                }
+            }
+        }
+        let Some(hovered) = monitors.iter().rev().find(|monitor| monitor.read().unwrap().contains_point(mouse_x, mouse_y)) else {
+            return false;
+        };
+        let mut hovered = hovered.write().unwrap();
+        if wheel != 0.0 {
+            hovered.scroll(if wheel > 0.0 { -1 } else { 1 });
+        }
+        if pressed {
+            hovered.click(mouse_x, mouse_y);
+        }
+        pressed
+    }
+    /// Everything drawn within the bounds apart from the given sprite, front to back.
+    fn layers_except(&self, sprite: &WrappedSprite, bounds: &Bounds) -> Vec<Silhouette> {
+        let sprites = self.sprites.read().unwrap().clone();
+        let mut layers: Vec<Silhouette> = sprites
+            .iter()
+            .rev()
+            .filter(|other| !Arc::ptr_eq(other, sprite))
+            .filter_map(|other| other.read().unwrap().silhouette())
+            .collect();
+        layers.extend(self.stage.read().unwrap().silhouette());
+        // Layers elsewhere on the stage would only be sampled to find transparent pixels
+        layers.retain(|layer| layer.bounds().is_some_and(|layer_bounds| layer_bounds.intersects(bounds)));
+        layers
+    }
+    /// Whether the stage under any of the sprite's matching pixels has the given color.
+    fn is_touching_color(&self, sprite: &WrappedSprite, color: [u8; 3], sprite_color: Option<[u8; 3]>) -> bool {
+        let Some(mut silhouette) = sprite.read().unwrap().silhouette() else {
+            return false;
+        };
+        // Like Scratch, a ghosted sprite still senses with its full shape and color
+        silhouette.effects.ghost = 0.0;
+        let Some(bounds) = silhouette.bounds() else {
+            return false;
+        };
+        let (left, right) = (bounds.left.max(-240.0).floor() as i32, bounds.right.min(240.0).ceil() as i32);
+        let (bottom, top) = (bounds.bottom.max(-180.0).floor() as i32, bounds.top.min(180.0).ceil() as i32);
+        let sampled = Bounds { left: left as f32, right: right as f32, bottom: bottom as f32, top: top as f32 };
+        let layers = self.layers_except(sprite, &sampled);
+        (left..=right).any(|x| {
+            (bottom..=top).any(|y| {
+                let (x, y) = (x as f32, y as f32);
+                let [r, g, b, a] = silhouette.color_at(x, y);
+                let matches_sprite_color = match sprite_color {
+                    Some(sprite_color) => a > 0 && mask_matches([r, g, b], sprite_color),
+                    None => silhouette.contains_point(x, y),
+                };
+                matches_sprite_color && color_matches(sample_stage_color(&layers, x, y), color)
+            })
+        })
+    }
+    /// Starts the click hats of the topmost sprite under the cursor, or the stage's if there is none,
+    /// restarting any that are still running.
+    fn click(&self, x: f32, y: f32) {
+        let sprites = self.sprites.read().unwrap().clone();
+        for sprite in sprites.iter().rev() {
+            let sprite = sprite.read().unwrap();
+            if sprite.contains_point(x, y) {
+                for hat in sprite.click_hats.iter() {
+                    hat.restart();
+                }
+                return;
+            }
+        }
+        for hat in self.stage.read().unwrap().click_hats.iter() {
+            hat.restart();
+        }
+    }
+    fn layer_of(sprites: &[WrappedSprite], sprite: &WrappedSprite) -> usize {
+        sprites.iter().position(|other| Arc::ptr_eq(other, sprite)).expect("Sprite is not part of the scene")
+    }
+    fn move_sprite_to_layer(&self, sprite: &WrappedSprite, layer: impl FnOnce(usize, usize) -> usize) {
+        let mut sprites = self.sprites.write().unwrap();
+        let current = Self::layer_of(&sprites, sprite);
+        let sprite = sprites.remove(current);
+        let layer = layer(current, sprites.len());
+        sprites.insert(layer, sprite);
+        scheduler::request_redraw();
+    }
+}
+
+#[no_mangle]
+pub fn new_scene(current_backdrop: i32) -> *const Scene {
+    let stage = Stage {
+        backdrops: Vec::new(),
+        // Backdrops are added afterwards, so only the lookups can catch an index past the end
+        current_backdrop: current_backdrop.max(0) as usize,
+        backdrop_hats: Vec::new(),
+        click_hats: Vec::new(),
+    };
+    Box::into_raw(Box::new(Scene {
+        sprites: RwLock::new(Vec::new()),
+        stage: RwLock::new(stage),
+        cursor: RwLock::new((0., 0.)),
+        prompt: Mutex::new(Prompt::default()),
+        question_answered: Condvar::new(),
+        answer: RwLock::new(String::new()),
+        keys_down: RwLock::new(HashSet::new()),
+        key_hats: RwLock::new(Vec::new()),
+        mouse_down: AtomicBool::new(false),
+        timer: Mutex::new(Timer::new(Clock::System(Instant::now()))),
+        monitors: RwLock::new(Vec::new()),
+    }))
+}
+
+#[no_mangle]
+pub fn scene_add_backdrop(scene: *const Scene, costume: *mut Costume) {
+    let scene = unsafe { &*scene };
+    let costume = unsafe { Box::from_raw(costume) };
+    scene.stage.write().unwrap().backdrops.push(*costume)
+}
+
+#[no_mangle]
+pub fn looks_switch_backdrop_to_number(scene: *const Scene, backdrop: f64) {
+    let scene = unsafe { &*scene };
+    let mut stage = scene.stage.write().unwrap();
+    if !stage.backdrops.is_empty() {
+        let index = wrap_costume_index(backdrop - 1.0, stage.backdrops.len());
+        stage.set_backdrop(index);
+    }
+}
+
+#[no_mangle]
+pub fn looks_switch_backdrop_to_name(scene: *const Scene, backdrop: *const String) {
+    let scene = unsafe { &*scene };
+    let backdrop = unsafe { &*backdrop };
+    scene.stage.write().unwrap().switch_backdrop_to(backdrop);
+}
+
+#[no_mangle]
+pub fn looks_next_backdrop(scene: *const Scene) {
+    let scene = unsafe { &*scene };
+    let mut stage = scene.stage.write().unwrap();
+    if !stage.backdrops.is_empty() {
+        let next = wrap_costume_index(stage.current_backdrop as f64 + 1.0, stage.backdrops.len());
+        stage.set_backdrop(next);
+    }
+}
+
+#[no_mangle]
+pub fn looks_backdrop_number(scene: *const Scene) -> f64 {
+    let scene = unsafe { &*scene };
+    scene.stage.read().unwrap().current_backdrop as f64 + 1.0
+}
+
+#[no_mangle]
+pub fn looks_backdrop_name(scene: *const Scene) -> *mut String {
+    let scene = unsafe { &*scene };
+    let stage = scene.stage.read().unwrap();
+    let name = stage.backdrops.get(stage.current_backdrop).map(|backdrop| backdrop.name.clone()).unwrap_or_default();
+    Box::into_raw(Box::new(name))
+}
+
+#[no_mangle]
+pub fn event_when_backdrop_switches_to(scene: *const Scene, backdrop: *const c_char, callback: extern "C" fn()) {
+    let scene = unsafe { &*scene };
+    let backdrop = unsafe { CStr::from_ptr(backdrop).to_str().unwrap().to_owned() };
+    scene.stage.write().unwrap().backdrop_hats.push((backdrop, Hat::new(callback)));
+}
+
+#[no_mangle]
+pub fn scene_add_sprite(scene: *const Scene, sprite: *const WrappedSprite) {
+    let scene = unsafe { &*scene };
+    let sprite = unsafe { &*sprite };
+    scene.sprites.write().unwrap().push(sprite.clone());
+}
+
+#[no_mangle]
+pub fn looks_go_to_front_back(scene: *const Scene, sprite: *const WrappedSprite, front: bool) {
+    let scene = unsafe { &*scene };
+    let sprite = unsafe { &*sprite };
+    scene.move_sprite_to_layer(sprite, |_, len| if front { len } else { 0 });
+}
+
+#[no_mangle]
+pub fn looks_go_forward_backward_layers(scene: *const Scene, sprite: *const WrappedSprite, forward: bool, layers: f64) {
+    let scene = unsafe { &*scene };
+    let sprite = unsafe { &*sprite };
+    let layers = if layers.is_nan() { 0.0 } else { layers };
+    let layers = if forward { layers } else { -layers };
+    scene.move_sprite_to_layer(sprite, |current, len| (current as f64 + layers).clamp(0.0, len as f64) as usize);
+}
+
+#[no_mangle]
+pub fn looks_layer_number(scene: *const Scene, sprite: *const WrappedSprite) -> f64 {
+    let scene = unsafe { &*scene };
+    let sprite = unsafe { &*sprite };
+    let sprites = scene.sprites.read().unwrap();
+    // The stage is layer 0, so sprites start at 1
+    Scene::layer_of(&sprites, sprite) as f64 + 1.0
+}
+
+#[no_mangle]
+pub fn sensing_ask_and_wait(scene: *const Scene, sprite: *const WrappedSprite, question: *const String) {
+    let scene: &'static Scene = unsafe { &*scene };
+    // The stage asks with a null sprite
+    let sprite = unsafe { sprite.as_ref() }.cloned();
+    let text = unsafe { &*question }.clone();
+    let mut prompt = scene.prompt.lock().unwrap();
+    let id = prompt.next_id;
+    prompt.next_id += 1;
+    prompt.questions.push_back(Question { id, sprite, text });
+    if prompt.questions.len() == 1 {
+        prompt.show_next_question();
+    }
+    // The window answers on the thread that runs the tasks, so they can't block on it
+    if scheduler::in_task() {
+        drop(prompt);
+        scheduler::set_on_stop(Some(Box::new(move || scene.prompt.lock().unwrap().withdraw(id))));
+        while scene.prompt.lock().unwrap().questions.iter().any(|question| question.id == id) {
+            scheduler::yield_tick();
+        }
+        scheduler::set_on_stop(None);
+        return;
+    }
+    let _prompt = scene
+        .question_answered
+        .wait_while(prompt, |prompt| prompt.questions.iter().any(|question| question.id == id))
+        .unwrap();
+}
+
+#[no_mangle]
+pub fn sensing_answer(scene: *const Scene) -> *mut String {
+    let scene = unsafe { &*scene };
+    let answer = scene.answer.read().unwrap().clone();
+    Box::into_raw(Box::new(answer))
+}
+
+#[no_mangle]
+pub fn sensing_key_pressed(scene: *const Scene, key: *const String) -> bool {
+    let scene = unsafe { &*scene };
+    let key = scratch_key(unsafe { &*key });
+    let keys_down = scene.keys_down.read().unwrap();
+    if key == "any" {
+        !keys_down.is_empty()
+    } else {
+        keys_down.contains(&key)
+    }
+}
+
+#[no_mangle]
+pub fn event_when_key_pressed(scene: *const Scene, key: *const c_char, callback: extern "C" fn()) {
+    let scene = unsafe { &*scene };
+    let key = scratch_key(unsafe { CStr::from_ptr(key) }.to_str().unwrap());
+    scene.key_hats.write().unwrap().push((key, Hat::new(callback)));
+}
+
+#[no_mangle]
+pub fn sensing_mouse_x(scene: *const Scene) -> f64 {
+    let scene = unsafe { &*scene };
+    let x = scene.cursor.read().unwrap().0.clamp(-240.0, 240.0);
+    (x as f64 + 0.5).floor()
+}
+
+#[no_mangle]
+pub fn sensing_mouse_y(scene: *const Scene) -> f64 {
+    let scene = unsafe { &*scene };
+    let y = scene.cursor.read().unwrap().1.clamp(-180.0, 180.0);
+    (y as f64 + 0.5).floor()
+}
+
+#[no_mangle]
+pub fn sensing_mouse_down(scene: *const Scene) -> bool {
+    let scene = unsafe { &*scene };
+    scene.mouse_down.load(Ordering::SeqCst)
+}
+
+#[no_mangle]
+pub fn event_when_this_sprite_clicked(sprite: *const WrappedSprite, callback: extern "C" fn()) {
+    let sprite = unsafe { &*sprite };
+    sprite.write().unwrap().click_hats.push(Hat::new(callback));
+}
+
+#[no_mangle]
+pub fn event_when_stage_clicked(scene: *const Scene, callback: extern "C" fn()) {
+    let scene = unsafe { &*scene };
+    scene.stage.write().unwrap().click_hats.push(Hat::new(callback));
+}
+
+#[no_mangle]
+pub fn sensing_touching_sprite(sprite: *const WrappedSprite, target: *const WrappedSprite) -> bool {
+    let sprite = unsafe { &*sprite };
+    let target = unsafe { &*target };
+    if Arc::ptr_eq(sprite, target) {
+        return false;
+    }
+    // Taken one after the other so that no two sprite locks are held at once
+    let Some(sprite) = sprite.read().unwrap().silhouette() else {
+        return false;
+    };
+    let Some(target) = target.read().unwrap().silhouette() else {
+        return false;
+    };
+    sprite.touches(&target)
+}
+
+#[no_mangle]
+pub fn sensing_touching_edge(sprite: *const WrappedSprite) -> bool {
+    let sprite = unsafe { &*sprite };
+    let silhouette = sprite.read().unwrap().silhouette();
+    silhouette.is_some_and(|silhouette| silhouette.touches_edge())
+}
+
+#[no_mangle]
+pub fn sensing_touching_mouse(sprite: *const WrappedSprite, scene: *const Scene) -> bool {
+    let sprite = unsafe { &*sprite };
+    let (x, y) = *unsafe { &*scene }.cursor.read().unwrap();
+    sprite.read().unwrap().contains_point(x, y)
+}
+
+#[no_mangle]
+pub fn sensing_touching_color(sprite: *const WrappedSprite, scene: *const Scene, color: f64) -> bool {
+    let sprite = unsafe { &*sprite };
+    let scene = unsafe { &*scene };
+    scene.is_touching_color(sprite, rgb_from_f64(color), None)
+}
+
+#[no_mangle]
+pub fn sensing_color_is_touching_color(sprite: *const WrappedSprite, scene: *const Scene, sprite_color: f64, color: f64) -> bool {
+    let sprite = unsafe { &*sprite };
+    let scene = unsafe { &*scene };
+    scene.is_touching_color(sprite, rgb_from_f64(color), Some(rgb_from_f64(sprite_color)))
+}
+
+#[no_mangle]
+pub fn sensing_timer(scene: *const Scene) -> f64 {
+    let scene = unsafe { &*scene };
+    scene.timer.lock().unwrap().seconds()
+}
+
+#[no_mangle]
+pub fn sensing_reset_timer(scene: *const Scene) {
+    let scene = unsafe { &*scene };
+    scene.timer.lock().unwrap().reset();
+}
+
+#[no_mangle]
+pub fn scene_set_clock(scene: *const Scene, clock: extern "C" fn() -> f64) {
+    let scene = unsafe { &*scene };
+    scene.timer.lock().unwrap().set_clock(Clock::External(clock));
+}
+
+#[no_mangle]
+pub fn event_when_timer_greater_than(scene: *const Scene, threshold: f64, callback: extern "C" fn()) {
+    let scene = unsafe { &*scene };
+    let hat = TimerHat { threshold, hat: Hat::new(callback), was_over: false };
+    scene.timer.lock().unwrap().hats.push(hat);
+}
+
+#[no_mangle]
+pub fn sprite_add_variable(sprite: *const WrappedSprite, name: *const c_char, variable: *const Variable) {
+    let sprite = unsafe { &*sprite };
+    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap().to_owned();
+    let variable = unsafe { &*variable };
+    sprite.write().unwrap().variables.insert(name, variable);
+}
+
+#[no_mangle]
+pub fn sensing_distance_to_sprite(sprite: *const WrappedSprite, target: *const WrappedSprite) -> f64 {
+    // A target that doesn't exist is treated as very far away
+    if target.is_null() {
+        return 10000.0;
+    }
+    let (x, y) = unsafe { &*sprite }.read().unwrap().position.get_position();
+    let (target_x, target_y) = unsafe { &*target }.read().unwrap().position.get_position();
+    (x as f64 - target_x as f64).hypot(y as f64 - target_y as f64)
+}
+
+#[no_mangle]
+pub fn sensing_distance_to_mouse(sprite: *const WrappedSprite, scene: *const Scene) -> f64 {
+    let (x, y) = unsafe { &*sprite }.read().unwrap().position.get_position();
+    (x as f64 - sensing_mouse_x(scene)).hypot(y as f64 - sensing_mouse_y(scene))
+}
+
+#[no_mangle]
+pub fn sensing_of_x_position(target: *const WrappedSprite) -> f64 {
+    motion_get_x(target)
+}
+
+#[no_mangle]
+pub fn sensing_of_y_position(target: *const WrappedSprite) -> f64 {
+    motion_get_y(target)
+}
+
+#[no_mangle]
+pub fn sensing_of_direction(target: *const WrappedSprite) -> f64 {
+    motion_get_direction(target)
+}
+
+#[no_mangle]
+pub fn sensing_of_costume_number(target: *const WrappedSprite) -> f64 {
+    looks_costume_number(target)
+}
+
+#[no_mangle]
+pub fn sensing_of_costume_name(target: *const WrappedSprite) -> *mut String {
+    looks_costume_name(target)
+}
+
+#[no_mangle]
+pub fn sensing_of_size(target: *const WrappedSprite) -> f64 {
+    // Unlike the size reporter, this one isn't rounded
+    let target = unsafe { &*target };
+    target.read().unwrap().size as f64
+}
+
+#[no_mangle]
+pub fn sensing_of_volume(target: *const WrappedSprite) -> f64 {
+    let target = unsafe { &*target };
+    target.read().unwrap().volume as f64
+}
+
+#[no_mangle]
+pub fn sensing_of_variable(target: *const WrappedSprite, name: *const String) -> *mut ScratchValue {
+    let target = unsafe { &*target };
+    let name = unsafe { &*name };
+    Box::into_raw(Box::new(target.read().unwrap().variable(name)))
+}
+
+#[no_mangle]
+pub fn new_variable_monitor(label: *const c_char, variable: *const Variable, x: f32, y: f32, mode: i32, slider_min: f64, slider_max: f64, visible: bool) -> *const WrappedMonitor {
+    let monitor = Monitor {
+        label: unsafe { CStr::from_ptr(label) }.to_str().unwrap().to_owned(),
+        x,
+        y,
+        visible,
+        source: MonitorSource::Variable {
+            variable: unsafe { &*variable },
+            mode: MonitorMode::from_i32(mode, slider_min, slider_max),
+        },
+        dragging_slider: false,
+    };
+    Box::into_raw(Box::new(Arc::new(RwLock::new(monitor))))
+}
+
+#[no_mangle]
+pub fn new_list_monitor(label: *const c_char, list: *const List, x: f32, y: f32, width: f32, height: f32, visible: bool) -> *const WrappedMonitor {
+    let monitor = Monitor {
+        label: unsafe { CStr::from_ptr(label) }.to_str().unwrap().to_owned(),
+        x,
+        y,
+        visible,
+        source: MonitorSource::List {
+            list: unsafe { &*list },
+            // Scratch's default size for list monitors
+            width: if width > 0.0 { width } else { 100.0 },
+            height: if height > 0.0 { height } else { 200.0 },
+            scroll: 0,
+        },
+        dragging_slider: false,
+    };
+    Box::into_raw(Box::new(Arc::new(RwLock::new(monitor))))
+}
+
+#[no_mangle]
+pub fn scene_add_monitor(scene: *const Scene, monitor: *const WrappedMonitor) {
+    let scene = unsafe { &*scene };
+    let monitor = unsafe { &*monitor };
+    scene.monitors.write().unwrap().push(monitor.clone());
+}
+
+#[no_mangle]
+pub fn data_show_variable(monitor: *const WrappedMonitor) {
+    let monitor = unsafe { &*monitor };
+    monitor.write().unwrap().visible = true;
+}
+
+#[no_mangle]
+pub fn data_hide_variable(monitor: *const WrappedMonitor) {
+    let monitor = unsafe { &*monitor };
+    monitor.write().unwrap().visible = false;
+}
+
+#[no_mangle]
+pub fn data_show_list(monitor: *const WrappedMonitor) {
+    data_show_variable(monitor);
+}
+
+#[no_mangle]
+pub fn data_hide_list(monitor: *const WrappedMonitor) {
+    data_hide_variable(monitor);
+}
+
+#[no_mangle]
+pub fn create_window(scene: *const Scene) {
+    let scene = unsafe { &*scene };
+    // The project clock starts with the window
+    scene.timer.lock().unwrap().reset();
+    Window::from_config(macroquad::conf::Conf {
+        miniquad_conf: miniquad::conf::Conf {
+            window_title: "Scratch".to_owned(),
+            window_width: 480,
+            window_height: 360,
+            high_dpi: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }, window_loop(scene));
+}
+
+async fn window_loop(scene: &Scene) {
+    let input_subscriber = register_input_subscriber();
+    loop {
+        let mut key_presses = KeyPresses::default();
+        repeat_all_miniquad_input(&mut key_presses, input_subscriber);
+        scene.handle_input(key_presses);
+        scene.timer.lock().unwrap().start_hats();
+        scheduler::step_frame();
+        clear_background(color::WHITE);
+        scene.draw();
+        next_frame().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::sync::mpsc::{self, Sender};
+
+    /// A solid red rectangle costume with the given rotation center.
+    fn rect_costume(width: u32, height: u32, center_x: i32, center_y: i32) -> *mut Costume {
+        filled_rect_costume(width, height, center_x, center_y, "#ff0000")
+    }
+
+    fn filled_rect_costume(width: u32, height: u32, center_x: i32, center_y: i32, fill: &str) -> *mut Costume {
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}"><rect width="{width}" height="{height}" fill="{fill}"/></svg>"#
+        );
+        let svg = CString::new(svg).unwrap();
+        let name = CString::new("costume").unwrap();
+        new_costume(svg.as_ptr(), name.as_ptr(), center_x, center_y) as *mut Costume
+    }
+
+    fn rect_sprite(width: u32, height: u32, x: f32, y: f32, direction: f32, rotation_style: i32, size: f32) -> *const WrappedSprite {
+        let sprite = new_sprite(0, x, y, direction, rotation_style, size, true);
+        motion_add_costume(sprite, rect_costume(width, height, width as i32 / 2, height as i32 / 2));
+        sprite
+    }
+
+    #[test]
+    fn touching_sprite_needs_overlapping_pixels() {
+        let sprite = rect_sprite(20, 20, 0.0, 0.0, 90.0, 0, 100.0);
+        let near = rect_sprite(4, 4, 11.0, 0.0, 90.0, 0, 100.0);
+        let far = rect_sprite(4, 4, 14.0, 0.0, 90.0, 0, 100.0);
+        assert!(sensing_touching_sprite(sprite, near));
+        assert!(sensing_touching_sprite(near, sprite));
+        assert!(!sensing_touching_sprite(sprite, far));
+        assert!(!sensing_touching_sprite(sprite, sprite));
+    }
+
+    #[test]
+    fn touching_sprite_follows_rotation() {
+        let bar = rect_sprite(40, 4, 0.0, 0.0, 90.0, 0, 100.0);
+        let above = rect_sprite(4, 4, 0.0, 15.0, 90.0, 0, 100.0);
+        assert!(!sensing_touching_sprite(bar, above));
+        motion_turn_left(bar, 90.0);
+        assert!(sensing_touching_sprite(bar, above));
+    }
+
+    #[test]
+    fn left_right_flip_mirrors_around_the_rotation_center() {
+        // The rotation center is on the left edge, so facing left puts the costume left of x = 0
+        let sprite = new_sprite(0, 0.0, 0.0, 90.0, 1, 100.0, true);
+        motion_add_costume(sprite, rect_costume(20, 10, 0, 5));
+        let left = rect_sprite(4, 4, -10.0, 0.0, 90.0, 0, 100.0);
+        let right = rect_sprite(4, 4, 10.0, 0.0, 90.0, 0, 100.0);
+        assert!(sensing_touching_sprite(sprite, right));
+        assert!(!sensing_touching_sprite(sprite, left));
+        motion_turn_left(sprite, 180.0);
+        assert!(sensing_touching_sprite(sprite, left));
+        assert!(!sensing_touching_sprite(sprite, right));
+    }
+
+    #[test]
+    fn touching_sprite_follows_size() {
+        let sprite = rect_sprite(10, 10, 0.0, 0.0, 90.0, 0, 100.0);
+        let target = rect_sprite(4, 4, 12.0, 0.0, 90.0, 0, 100.0);
+        assert!(!sensing_touching_sprite(sprite, target));
+        looks_set_size_to(sprite, 300.0);
+        assert!(sensing_touching_sprite(sprite, target));
+    }
+
+    #[test]
+    fn hidden_sprites_touch_nothing() {
+        let sprite = rect_sprite(20, 20, 0.0, 0.0, 90.0, 0, 100.0);
+        let target = rect_sprite(20, 20, 5.0, 0.0, 90.0, 0, 100.0);
+        assert!(sensing_touching_sprite(sprite, target));
+        looks_hide(target);
+        assert!(!sensing_touching_sprite(sprite, target));
+        looks_show(target);
+        looks_hide(sprite);
+        assert!(!sensing_touching_sprite(sprite, target));
+        assert!(!sensing_touching_edge(sprite));
+    }
+
+    #[test]
+    fn touching_edge_only_past_the_stage() {
+        let sprite = rect_sprite(20, 20, 0.0, 0.0, 90.0, 0, 100.0);
+        assert!(!sensing_touching_edge(sprite));
+        motion_set_x(sprite, 235.0);
+        assert!(sensing_touching_edge(sprite));
+        motion_set_x(sprite, 0.0);
+        motion_set_y(sprite, -175.0);
+        assert!(sensing_touching_edge(sprite));
+    }
+
+    /// A red sprite with a blue one overlapping its right edge.
+    fn red_and_blue_scene() -> (*const Scene, *const WrappedSprite, *const WrappedSprite) {
+        let scene = new_scene(0);
+        let red = rect_sprite(20, 20, 0.0, 0.0, 90.0, 0, 100.0);
+        let blue = new_sprite(0, 14.0, 0.0, 90.0, 0, 100.0, true);
+        motion_add_costume(blue, filled_rect_costume(10, 10, 5, 5, "#0000ff"));
+        scene_add_sprite(scene, red);
+        scene_add_sprite(scene, blue);
+        (scene, red, blue)
+    }
+
+    #[test]
+    fn touching_color_sees_other_sprites_and_the_stage() {
+        let (scene, red, blue) = red_and_blue_scene();
+        assert!(sensing_touching_color(red, scene, 0x0000ff as f64));
+        // Within the tolerance of 4 bits of blue
+        assert!(sensing_touching_color(red, scene, 0x0000f3 as f64));
+        assert!(!sensing_touching_color(red, scene, 0x00ff00 as f64));
+        // The sprite's own color doesn't count, but the white stage around it does
+        assert!(!sensing_touching_color(red, scene, 0xff0000 as f64));
+        assert!(sensing_touching_color(red, scene, 0xffffff as f64));
+        motion_set_x(blue, 40.0);
+        assert!(!sensing_touching_color(red, scene, 0x0000ff as f64));
+    }
+
+    #[test]
+    fn color_touching_color_uses_the_stricter_mask_tolerance() {
+        let (scene, red, _) = red_and_blue_scene();
+        assert!(sensing_color_is_touching_color(red, scene, 0xff0000 as f64, 0x0000ff as f64));
+        // The sprite's color only ignores the lowest 2 bits of each channel
+        assert!(sensing_color_is_touching_color(red, scene, 0xfc0000 as f64, 0x0000ff as f64));
+        assert!(!sensing_color_is_touching_color(red, scene, 0xf80000 as f64, 0x0000ff as f64));
+        assert!(!sensing_color_is_touching_color(red, scene, 0x00ff00 as f64, 0x0000ff as f64));
+    }
+
+    #[test]
+    fn sensing_of_variable_reads_the_shared_cell() {
+        let sprite = rect_sprite(4, 4, 0.0, 0.0, 90.0, 0, 100.0);
+        let variable = crate::alloc_variable(&ScratchValue::Number(10.0));
+        let name = CString::new("score").unwrap();
+        sprite_add_variable(sprite, name.as_ptr(), variable);
+        let value = unsafe { Box::from_raw(sensing_of_variable(sprite, &"score".to_owned())) };
+        assert!(matches!(*value, ScratchValue::Number(n) if n == 10.0));
+        crate::set_variable(variable, &ScratchValue::String("high".to_owned()));
+        let value = unsafe { Box::from_raw(sensing_of_variable(sprite, &"score".to_owned())) };
+        assert!(matches!(*value, ScratchValue::String(ref s) if s == "high"));
+        let missing = unsafe { Box::from_raw(sensing_of_variable(sprite, &"lives".to_owned())) };
+        assert!(matches!(*missing, ScratchValue::Number(n) if n == 0.0));
+    }
+
+    #[test]
+    fn costume_name_without_costumes_is_empty() {
+        let sprite = new_sprite(0, 0.0, 0.0, 90.0, 0, 100.0, true);
+        let name = unsafe { Box::from_raw(looks_costume_name(sprite)) };
+        assert_eq!(*name, "");
+        let sprite = new_sprite(-1, 0.0, 0.0, 90.0, 0, 100.0, true);
+        motion_add_costume(sprite, rect_costume(4, 4, 2, 2));
+        let name = unsafe { Box::from_raw(looks_costume_name(sprite)) };
+        assert_eq!(*name, "costume");
+    }
+
+    #[test]
+    fn costume_name_of_a_sprite_without_costumes_is_empty() {
+        let target = new_sprite(2, 0.0, 0.0, 90.0, 0, 100.0, true);
+        let name = unsafe { Box::from_raw(sensing_of_costume_name(target)) };
+        assert_eq!(*name, "");
+        motion_add_costume(target, rect_costume(4, 4, 2, 2));
+        looks_switch_costume_to_number(target, 1.0);
+        let name = unsafe { Box::from_raw(sensing_of_costume_name(target)) };
+        assert_eq!(*name, "costume");
+    }
+
+    #[test]
+    fn backdrop_name_without_backdrops_is_empty() {
+        let scene = new_scene(0);
+        let name = unsafe { Box::from_raw(looks_backdrop_name(scene)) };
+        assert_eq!(*name, "");
+        let scene = new_scene(3);
+        scene_add_backdrop(scene, rect_costume(4, 4, 2, 2));
+        let name = unsafe { Box::from_raw(looks_backdrop_name(scene)) };
+        assert_eq!(*name, "");
+    }
+
+    #[test]
+    fn negative_backdrop_index_starts_at_the_first_backdrop() {
+        let scene = new_scene(-1);
+        scene_add_backdrop(scene, rect_costume(4, 4, 2, 2));
+        assert_eq!(looks_backdrop_number(scene), 1.0);
+        let name = unsafe { Box::from_raw(looks_backdrop_name(scene)) };
+        assert_eq!(*name, "costume");
+    }
+
+    static FAKE_SECONDS: Mutex<f64> = Mutex::new(0.0);
+    static TIMER_HAT_RAN: Mutex<Option<Sender<()>>> = Mutex::new(None);
+
+    extern "C" fn fake_clock() -> f64 {
+        *FAKE_SECONDS.lock().unwrap()
+    }
+
+    extern "C" fn report_timer_hat_run() {
+        TIMER_HAT_RAN.lock().unwrap().as_ref().unwrap().send(()).unwrap();
+    }
+
+    #[test]
+    fn timer_follows_the_injected_clock() {
+        *FAKE_SECONDS.lock().unwrap() = 100.0;
+        let (sender, runs) = mpsc::channel();
+        *TIMER_HAT_RAN.lock().unwrap() = Some(sender);
+        let scene = new_scene(0);
+        scene_set_clock(scene, fake_clock);
+        event_when_timer_greater_than(scene, 1.5, report_timer_hat_run);
+        let set_seconds = |seconds: f64| *FAKE_SECONDS.lock().unwrap() = seconds;
+        // Hats run on their own thread, which reports the run before it's done
+        let runs_after_starting_hats = || {
+            let mut timer = unsafe { &*scene }.timer.lock().unwrap();
+            timer.start_hats();
+            while timer.hats.iter().any(|timer_hat| timer_hat.hat.running.load(Ordering::SeqCst)) {
+                thread::yield_now();
+            }
+            runs.try_iter().count()
+        };
+
+        assert_eq!(sensing_timer(scene), 0.0);
+        set_seconds(101.0);
+        assert_eq!(sensing_timer(scene), 1.0);
+        assert_eq!(runs_after_starting_hats(), 0);
+
+        // Fires once when the threshold is crossed, not on every frame after
+        set_seconds(102.0);
+        assert_eq!(runs_after_starting_hats(), 1);
+        set_seconds(103.0);
+        assert_eq!(runs_after_starting_hats(), 0);
+
+        sensing_reset_timer(scene);
+        assert_eq!(sensing_timer(scene), 0.0);
+        assert_eq!(runs_after_starting_hats(), 0);
+        set_seconds(105.0);
+        assert_eq!(runs_after_starting_hats(), 1);
     }
 }
@@ -0,0 +1,114 @@
+use crate::{number_to_string, parse_number, ToScratchString};
+
+/// A value as Scratch sees it: variables and list items can hold any of these.
+#[derive(Clone, Debug)]
+pub enum ScratchValue {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+impl ScratchValue {
+    /// Converts the value like JavaScript's `Number()`, which can produce `NaN`.
+    pub(crate) fn to_js_number(&self) -> f64 {
+        match self {
+            Self::Number(n) => *n,
+            Self::String(string) => parse_number(string).unwrap_or(f64::NAN),
+            Self::Bool(b) => *b as u8 as f64,
+        }
+    }
+    /// Converts the value like Scratch's `Cast.toNumber`, where anything that isn't a number is 0.
+    pub(crate) fn to_number(&self) -> f64 {
+        let n = self.to_js_number();
+        if n.is_nan() {
+            0.0
+        } else {
+            n
+        }
+    }
+    pub(crate) fn to_bool(&self) -> bool {
+        match self {
+            Self::Number(n) => *n != 0.0 && !n.is_nan(),
+            Self::String(string) => {
+                !(string.is_empty() || string == "0" || string.eq_ignore_ascii_case("false"))
+            }
+            Self::Bool(b) => *b,
+        }
+    }
+    pub(crate) fn is_whitespace(&self) -> bool {
+        match self {
+            Self::String(string) => string
+                .trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}')
+                .is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl ToScratchString for ScratchValue {
+    fn to_scratch_string(&self) -> String {
+        match self {
+            Self::Number(n) => number_to_string(*n),
+            Self::String(string) => string.clone(),
+            Self::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+impl Default for ScratchValue {
+    fn default() -> Self {
+        Self::String(String::new())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn alloc_number_value(value: f64) -> *mut ScratchValue {
+    Box::into_raw(Box::new(ScratchValue::Number(value)))
+}
+
+#[no_mangle]
+pub extern "C" fn alloc_string_value(value: *const String) -> *mut ScratchValue {
+    let value = unsafe { &*value };
+    Box::into_raw(Box::new(ScratchValue::String(value.clone())))
+}
+
+#[no_mangle]
+pub extern "C" fn alloc_bool_value(value: bool) -> *mut ScratchValue {
+    Box::into_raw(Box::new(ScratchValue::Bool(value)))
+}
+
+#[no_mangle]
+pub extern "C" fn free_value(ptr: *mut ScratchValue) {
+    unsafe {
+        let _ = Box::from_raw(ptr);
+    }
+}
+
+/// 0 for numbers, 1 for strings and 2 for booleans.
+#[no_mangle]
+pub extern "C" fn value_kind(value: *const ScratchValue) -> u8 {
+    let value = unsafe { &*value };
+    match value {
+        ScratchValue::Number(_) => 0,
+        ScratchValue::String(_) => 1,
+        ScratchValue::Bool(_) => 2,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cast_value_to_f64(value: *const ScratchValue) -> f64 {
+    let value = unsafe { &*value };
+    value.to_number()
+}
+
+#[no_mangle]
+pub extern "C" fn cast_value_to_string(value: *const ScratchValue) -> *mut String {
+    let value = unsafe { &*value };
+    Box::into_raw(Box::new(value.to_scratch_string()))
+}
+
+#[no_mangle]
+pub extern "C" fn cast_value_to_bool(value: *const ScratchValue) -> bool {
+    let value = unsafe { &*value };
+    value.to_bool()
+}